@@ -0,0 +1,121 @@
+// Relay throughput and cpu time, buffered copy vs splice(2):
+//   cargo bench --bench relay [-- <MiB per run>]
+// A fake socks upstream streams the data through `transfer` to a client on localhost.
+use dyn_tor::proxy::{transfer, CloseReason, Timeouts};
+use dyn_tor::{InstanceInfo, InstanceStatus, SocksAddr};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+const RUNS: usize = 5;
+const CHUNK_SIZE: usize = 256 * 1024;
+
+// socks5 without auth, then `total` bytes
+async fn upstream(listener: TcpListener, total: usize) {
+    let chunk = vec![0x5au8; CHUNK_SIZE];
+    let (mut stream, _) = listener.accept().await.unwrap();
+    let mut greeting = [0u8; 3];
+    stream.read_exact(&mut greeting).await.unwrap();
+    stream.write_all(&[5, 0]).await.unwrap();
+    let mut request = [0u8; 10];
+    stream.read_exact(&mut request).await.unwrap();
+    stream.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 80]).await.unwrap();
+    let mut left = total;
+    while left > 0 {
+        let n = left.min(chunk.len());
+        stream.write_all(&chunk[..n]).await.unwrap();
+        left -= n;
+    }
+}
+
+async fn client(addr: SocketAddr) -> usize {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(&[5, 1, 0]).await.unwrap();
+    stream.write_all(&[5, 1, 0, 1, 127, 0, 0, 1, 0, 80]).await.unwrap();
+    let mut reply = [0u8; 12];
+    stream.read_exact(&mut reply).await.unwrap();
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut received = 0;
+    loop {
+        match stream.read(&mut buf).await.unwrap() {
+            0 => return received,
+            n => received += n,
+        }
+    }
+}
+
+// of the calling thread
+#[cfg(target_os = "linux")]
+fn cpu_time() -> Duration {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    unsafe { libc::getrusage(libc::RUSAGE_THREAD, &mut usage) };
+    let time = |x: libc::timeval| Duration::new(x.tv_sec as u64, x.tv_usec as u32 * 1000);
+    time(usage.ru_utime) + time(usage.ru_stime)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn cpu_time() -> Duration {
+    Duration::ZERO
+}
+
+async fn run(total: usize, splice: bool) -> (Duration, Duration) {
+    let upstream_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let instance = InstanceInfo {
+        index: 0,
+        backend: "bench",
+        socks_addr: SocksAddr::Tcp(upstream_listener.local_addr().unwrap().to_string()),
+        credentials: None,
+        data_dir: None,
+        group: None,
+        pid: None,
+        status: InstanceStatus::Ready,
+    };
+    let upstream = tokio::spawn(upstream(upstream_listener, total));
+    let proxy_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy_addr = proxy_listener.local_addr().unwrap();
+    let proxy_listener = proxy_listener.into_std().unwrap();
+    // own thread, so its cpu time is the relay only
+    let proxy = std::thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        runtime.block_on(async move {
+            let proxy_listener = TcpListener::from_std(proxy_listener).unwrap();
+            let (inbound, _) = proxy_listener.accept().await.unwrap();
+            let cpu_start = cpu_time();
+            let closed = transfer(inbound, &instance, &Timeouts::default(), splice).await;
+            (closed, cpu_time() - cpu_start)
+        })
+    });
+
+    let start = Instant::now();
+    let received = client(proxy_addr).await;
+    let (closed, cpu) = proxy.join().unwrap();
+    let res = (start.elapsed(), cpu);
+    upstream.await.unwrap();
+    assert_eq!(received, total);
+    assert_eq!(closed.reason, CloseReason::Done);
+    assert_eq!(closed.bytes_down as usize, total);
+    res
+}
+
+fn main() {
+    let mib: usize = std::env::args()
+        .skip(1)
+        .find_map(|x| x.parse().ok())
+        .unwrap_or(1024);
+    let total = mib * 1024 * 1024;
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    for (name, splice) in [("buffered", false), ("splice", true)] {
+        let runs: Vec<_> = (0..RUNS).map(|_| runtime.block_on(run(total, splice))).collect();
+        let elapsed = runs.iter().map(|x| x.0).min().unwrap();
+        let cpu = runs.iter().map(|x| x.1).min().unwrap();
+        println!(
+            "{:>8}: {} MiB in {:?} ({:.0} MiB/s), relay cpu {:?}",
+            name,
+            mib,
+            elapsed,
+            mib as f64 / elapsed.as_secs_f64(),
+            cpu
+        );
+    }
+}
@@ -0,0 +1,150 @@
+// Stand-in for tor in the integration tests (tests/e2e.rs), no network needed.
+// Takes the arguments dyn_tor passes (-f, --SocksPort, --DataDirectory, --version,
+// --verify-config), prints bootstrap lines and serves SOCKS5 on the given port or unix socket:
+// every CONNECT succeeds, then the "remote" sends the data dir name and a newline and echoes.
+// torrc options only the fake understands:
+//   FakeTorReject 1     --verify-config fails
+//   FakeTorExit <code>  exit with <code> right after start, before bootstrapping
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::path::Path;
+
+const VERSION: &str = "Tor version 0.4.8.10.";
+
+fn notice(msg: &str) {
+    println!("Jan 01 00:00:00.000 [notice] {}", msg);
+}
+
+fn read_torrc(path: &str) -> HashMap<String, String> {
+    let data = std::fs::read_to_string(path).unwrap_or_else(|e| {
+        println!("[warn] Unable to open configuration file \"{}\": {}", path, e);
+        std::process::exit(1);
+    });
+    data.lines()
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty() && !x.starts_with('#'))
+        .map(|x| match x.split_once(char::is_whitespace) {
+            Some((key, value)) => (key.to_string(), value.trim().to_string()),
+            None => (x.to_string(), String::new()),
+        })
+        .collect()
+}
+
+fn serve<S: Read + Write>(mut stream: S, name: &str) -> std::io::Result<()> {
+    // VER NMETHODS METHODS, no auth
+    let mut header = [0u8; 2];
+    stream.read_exact(&mut header)?;
+    let mut methods = vec![0u8; header[1] as usize];
+    stream.read_exact(&mut methods)?;
+    stream.write_all(&[5, 0])?;
+    // VER CMD RSV ATYP DST.ADDR DST.PORT
+    let mut request = [0u8; 4];
+    stream.read_exact(&mut request)?;
+    let addr_len = match request[3] {
+        1 => 4,
+        4 => 16,
+        _ => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len)?;
+            len[0] as usize
+        }
+    };
+    let mut addr = vec![0u8; addr_len + 2];
+    stream.read_exact(&mut addr)?;
+    stream.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 80])?;
+    stream.write_all(format!("{}\n", name).as_bytes())?;
+    let mut buf = [0u8; 4096];
+    loop {
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            return Ok(());
+        }
+        stream.write_all(&buf[..n])?;
+    }
+}
+
+enum Listener {
+    Tcp(std::net::TcpListener),
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixListener),
+}
+
+impl Listener {
+    fn bind(socks_port: &str) -> std::io::Result<Self> {
+        #[cfg(unix)]
+        if let Some(path) = socks_port.strip_prefix("unix:") {
+            return Ok(Listener::Unix(std::os::unix::net::UnixListener::bind(path)?));
+        }
+        // "port" or "address:port"
+        let addr = match socks_port.parse::<u16>() {
+            Ok(port) => format!("127.0.0.1:{}", port),
+            Err(_) => socks_port.to_string(),
+        };
+        Ok(Listener::Tcp(std::net::TcpListener::bind(addr)?))
+    }
+
+    fn run(self, name: String) {
+        match self {
+            Listener::Tcp(listener) => {
+                for stream in listener.incoming().flatten() {
+                    let name = name.clone();
+                    std::thread::spawn(move || serve(stream, &name));
+                }
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                for stream in listener.incoming().flatten() {
+                    let name = name.clone();
+                    std::thread::spawn(move || serve(stream, &name));
+                }
+            }
+        }
+    }
+}
+
+fn main() {
+    let mut options: HashMap<String, String> = HashMap::new();
+    let mut verify = false;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--version" => {
+                println!("{}", VERSION);
+                return;
+            }
+            "--verify-config" => verify = true,
+            _ => {
+                let name = arg.trim_start_matches('-').to_string();
+                options.insert(name, args.next().unwrap_or_default());
+            }
+        }
+    }
+    let torrc = read_torrc(options.get("f").map(|x| x.as_str()).unwrap_or("/etc/tor/torrc"));
+    if verify {
+        if torrc.contains_key("FakeTorReject") {
+            println!("[warn] Failed to parse/validate config: Unknown option 'FakeTorReject'.");
+            std::process::exit(1);
+        }
+        println!("Configuration was valid");
+        return;
+    }
+
+    notice(VERSION);
+    if let Some(code) = torrc.get("FakeTorExit") {
+        println!("[err] exiting as asked by FakeTorExit");
+        std::process::exit(code.parse().unwrap_or(1));
+    }
+    let data_dir = options.get("DataDirectory").cloned().unwrap_or_default();
+    let name = Path::new(&data_dir)
+        .file_name()
+        .map(|x| x.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let socks_port = options.get("SocksPort").map(|x| x.as_str()).unwrap_or("9050");
+    let listener = Listener::bind(socks_port).unwrap_or_else(|e| {
+        println!("[warn] Could not bind to {}: {}", socks_port, e);
+        std::process::exit(1);
+    });
+    notice("Bootstrapped 0% (starting): Starting");
+    notice("Bootstrapped 100% (done): Done");
+    listener.run(name);
+}
@@ -0,0 +1,136 @@
+use crate::config::ProxyConfig;
+use crate::error::ConfigFileError;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+// Address range "10.0.0.0/8", "::1/128"; a plain address is a single host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // ipv4 clients of a dual stack listener come as ::ffff:a.b.c.d
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => prefix_matches(u32::from(net) as u128, u32::from(ip) as u128, 32, self.prefix),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => prefix_matches(u128::from(net), u128::from(ip), 128, self.prefix),
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(net: u128, ip: u128, bits: u8, prefix: u8) -> bool {
+    if prefix == 0 {
+        return true;
+    }
+    let shift = bits - prefix;
+    net >> shift == ip >> shift
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr = IpAddr::from_str(addr.trim()).map_err(|e| e.to_string())?.to_canonical();
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.trim().parse::<u8>().map_err(|e| format!("prefix: {}", e))?,
+            None => bits,
+        };
+        if prefix > bits {
+            return Err(format!("prefix /{} is longer than the address", prefix));
+        }
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl std::fmt::Display for Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+// Client addresses the listener serves: a denied one never is, otherwise it has to be allowed
+// (everything is when the allow list is empty).
+#[derive(Debug, Clone, Default)]
+pub struct AccessList {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+fn parse_list(parameter: &str, list: &[String]) -> Result<Vec<Cidr>, ConfigFileError> {
+    list.iter()
+        .map(|x| {
+            x.parse().map_err(|error| ConfigFileError::Cidr {
+                parameter: parameter.to_string(),
+                value: x.clone(),
+                error,
+            })
+        })
+        .collect()
+}
+
+impl AccessList {
+    pub fn from_config(config: &ProxyConfig) -> Result<Self, ConfigFileError> {
+        Ok(Self {
+            allow: parse_list("proxy.allow", &config.allow)?,
+            deny: parse_list("proxy.deny", &config.deny)?,
+        })
+    }
+
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|x| x.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|x| x.contains(ip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::access::{AccessList, Cidr};
+    use crate::config::ProxyConfig;
+    use std::net::IpAddr;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn check_access_list() {
+        let cidr: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(cidr.contains(ip("10.1.2.3")));
+        assert!(cidr.contains(ip("::ffff:10.1.2.3")));
+        assert!(!cidr.contains(ip("10.2.0.1")));
+        assert!(!cidr.contains(ip("::1")));
+        assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains(ip("1.2.3.4")));
+        assert!("fd00::/8".parse::<Cidr>().unwrap().contains(ip("fd12::1")));
+        assert_eq!("127.0.0.1".parse::<Cidr>().unwrap().to_string(), "127.0.0.1/32");
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+
+        assert!(AccessList::default().is_allowed(ip("1.2.3.4")));
+        let config = ProxyConfig {
+            allow: vec!["127.0.0.0/8".to_string(), "10.0.0.0/8".to_string()],
+            deny: vec!["10.9.0.0/16".to_string()],
+            ..Default::default()
+        };
+        let list = AccessList::from_config(&config).unwrap();
+        assert!(list.is_allowed(ip("127.0.0.1")));
+        assert!(list.is_allowed(ip("10.1.1.1")));
+        assert!(!list.is_allowed(ip("10.9.1.1")));
+        assert!(!list.is_allowed(ip("192.168.1.1")));
+
+        let config = ProxyConfig {
+            deny: vec!["not an address".to_string()],
+            ..Default::default()
+        };
+        assert!(AccessList::from_config(&config).is_err());
+    }
+}
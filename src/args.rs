@@ -0,0 +1,32 @@
+use dyn_tor::error::ArgsError;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RunCommand {
+    Run,
+    // validate everything and print the planned pool without starting it
+    DryRun,
+    CheckConfig { show_effective: bool },
+    // password from stdin -> hash for proxy.users
+    HashPassword,
+}
+
+pub fn parse_args<I>(args: I) -> Result<RunCommand, ArgsError>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut res = RunCommand::Run;
+    for arg in args {
+        match (&mut res, arg.as_str()) {
+            (RunCommand::Run, "--dry-run") => res = RunCommand::DryRun,
+            (RunCommand::Run, "hash-password") => res = RunCommand::HashPassword,
+            (RunCommand::Run, "check-config") => {
+                res = RunCommand::CheckConfig {
+                    show_effective: false,
+                }
+            }
+            (RunCommand::CheckConfig { show_effective }, "--show-effective") => *show_effective = true,
+            _ => return Err(ArgsError::Unknown { arg }),
+        }
+    }
+    Ok(res)
+}
@@ -0,0 +1,255 @@
+use crate::config::{self, ProxyConfig, UserConfig};
+use crate::error::{AuthError, ConfigFileError};
+use crate::password::{self, PasswordHash};
+use hmac::{Hmac, Mac};
+use lru::LruCache;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use tokio::time::Instant;
+
+// logins remembered as verified, least recently used dropped first
+const VERIFIED_CAPACITY: usize = 1024;
+// client addresses whose failed logins are counted
+const FAILURES_CAPACITY: usize = 4096;
+const FAILURE_WINDOW: Duration = Duration::from_secs(60);
+
+struct User {
+    config: UserConfig,
+    hash: PasswordHash,
+}
+
+// failed logins of a client address since the window began
+struct Failures {
+    count: u32,
+    since: Instant,
+}
+
+// Client users from proxy.users and proxy.users_file. Empty: no authentication.
+pub struct Users {
+    users: HashMap<String, User>,
+    // checked for unknown usernames, as costly as the costliest user's hash
+    dummy: PasswordHash,
+    // per client address and FAILURE_WINDOW, 0: unlimited
+    max_failures: u32,
+    // keys of logins verified before (see verified_key): clients open many connections,
+    // each would cost a full password hash otherwise
+    verified: Mutex<LruCache<[u8; 32], ()>>,
+    failures: Mutex<LruCache<IpAddr, Failures>>,
+}
+
+impl Default for Users {
+    fn default() -> Self {
+        Self {
+            users: HashMap::new(),
+            dummy: PasswordHash::dummy(password::DEFAULT_ITERATIONS),
+            max_failures: 0,
+            verified: Mutex::new(LruCache::new(NonZeroUsize::new(VERIFIED_CAPACITY).unwrap())),
+            failures: Mutex::new(LruCache::new(NonZeroUsize::new(FAILURES_CAPACITY).unwrap())),
+        }
+    }
+}
+
+fn user_error(location: &str, username: &str, error: String) -> ConfigFileError {
+    ConfigFileError::User {
+        location: location.to_string(),
+        username: username.to_string(),
+        error,
+    }
+}
+
+// Random per process: cached keys can't be brute-forced from a memory dump any faster than
+// the password hashes, nor compared across runs. None (no OS randomness): nothing is cached.
+fn verified_secret() -> Option<&'static [u8; 32]> {
+    static SECRET: OnceLock<Option<[u8; 32]>> = OnceLock::new();
+    SECRET
+        .get_or_init(|| {
+            let mut secret = [0u8; 32];
+            getrandom::getrandom(&mut secret).ok().map(|_| secret)
+        })
+        .as_ref()
+}
+
+// HMAC-SHA256 of username and password under the process secret
+fn verified_key(username: &str, password: &str) -> Option<[u8; 32]> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(verified_secret()?).ok()?;
+    mac.update(username.as_bytes());
+    mac.update(&[0]);
+    mac.update(password.as_bytes());
+    Some(mac.finalize().into_bytes().into())
+}
+
+impl Users {
+    fn add(&mut self, location: &str, list: Vec<UserConfig>) -> Result<(), ConfigFileError> {
+        for config in list {
+            let hash = config
+                .password_hash
+                .parse()
+                .map_err(|e| user_error(location, &config.username, format!("password_hash: {}", e)))?;
+            if self.users.contains_key(&config.username) {
+                return Err(user_error(location, &config.username, "defined more than once".to_string()));
+            }
+            self.users.insert(config.username.clone(), User { config, hash });
+        }
+        Ok(())
+    }
+
+    // users_file_full_path has to be set (init)
+    pub fn from_config(config: &ProxyConfig) -> Result<Self, ConfigFileError> {
+        let mut res = Self::default();
+        res.add("proxy.users", config.users.clone())?;
+        if !config.users_file_full_path.is_empty() {
+            let path = &config.users_file_full_path;
+            let list = serde_json::from_value(config::read_config_value(Path::new(path))?).map_err(|e| {
+                ConfigFileError::Parse {
+                    path: path.clone(),
+                    error: e.to_string(),
+                }
+            })?;
+            res.add(path, list)?;
+        }
+        if let Some(iterations) = res.users.values().map(|x| x.hash.iterations()).max() {
+            res.dummy = PasswordHash::dummy(iterations);
+        }
+        res.max_failures = config.max_auth_failures_per_minute;
+        Ok(res)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+
+    // The user if the password is right. Hashing runs on a blocking thread; unknown usernames
+    // are checked against the dummy hash, so they take as long to refuse as wrong passwords.
+    pub async fn authenticate(&self, username: &str, password: &str, client: IpAddr) -> Result<UserConfig, AuthError> {
+        let user = self.users.get(username);
+        // a hit needs the right password, so its speed tells a client nothing it didn't know
+        let key = verified_key(username, password);
+        if let (Some(user), Some(key)) = (user, &key) {
+            if self.verified.lock().unwrap().get(key).is_some() {
+                return Ok(user.config.clone());
+            }
+        }
+        if !self.count_attempt(client) {
+            return Err(AuthError::TooManyFailures { client });
+        }
+        let hash = user.map_or(&self.dummy, |x| &x.hash).clone();
+        let password = password.to_string();
+        let valid = tokio::task::spawn_blocking(move || hash.verify(&password)).await.unwrap_or(false);
+        match user {
+            Some(user) if valid => {
+                if let Some(key) = key {
+                    self.verified.lock().unwrap().put(key, ());
+                }
+                self.uncount_attempt(client);
+                Ok(user.config.clone())
+            }
+            _ => Err(AuthError::WrongCredentials {
+                username: username.to_string(),
+            }),
+        }
+    }
+
+    // Counted as failed before hashing, so parallel attempts can't all slip through, and taken
+    // back on success. False when the client is over the limit: refused without hashing.
+    fn count_attempt(&self, client: IpAddr) -> bool {
+        if self.max_failures == 0 {
+            return true;
+        }
+        let now = Instant::now();
+        let mut failures = self.failures.lock().unwrap();
+        let entry = failures.get_or_insert_mut(client, || Failures { count: 0, since: now });
+        if now.duration_since(entry.since) >= FAILURE_WINDOW {
+            *entry = Failures { count: 0, since: now };
+        }
+        if entry.count >= self.max_failures {
+            return false;
+        }
+        entry.count += 1;
+        true
+    }
+
+    fn uncount_attempt(&self, client: IpAddr) {
+        if let Some(entry) = self.failures.lock().unwrap().get_mut(&client) {
+            entry.count = entry.count.saturating_sub(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::auth::{verified_key, Users, FAILURE_WINDOW};
+    use crate::config::{ProxyConfig, UserConfig};
+    use crate::error::AuthError;
+    use crate::password::PasswordHash;
+    use sha2::Digest;
+    use std::net::IpAddr;
+
+    fn user(username: &str, password_hash: String) -> UserConfig {
+        UserConfig {
+            username: username.to_string(),
+            password_hash,
+            groups: vec!["eu".to_string()],
+            max_connections: Some(2),
+        }
+    }
+
+    #[tokio::test]
+    async fn check_users() {
+        let dir = std::env::temp_dir().join(format!("dyn_tor_users_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("users.json");
+        let from_file = vec![user("bob", PasswordHash::new("bob pw", 10).unwrap().to_string())];
+        std::fs::write(&file, serde_json::to_string(&from_file).unwrap()).unwrap();
+        let mut config = ProxyConfig {
+            users: vec![user("alice", PasswordHash::new("alice pw", 10).unwrap().to_string())],
+            users_file_full_path: file.to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        let users = Users::from_config(&config).unwrap();
+        let alice = users.authenticate("alice", "alice pw", client).await.unwrap();
+        assert_eq!(alice.groups, vec!["eu".to_string()]);
+        assert_eq!(alice.max_connections, Some(2));
+        // verified before
+        assert!(users.authenticate("alice", "alice pw", client).await.is_ok());
+        assert!(users.authenticate("alice", "bob pw", client).await.is_err());
+        assert!(users.authenticate("bob", "bob pw", client).await.is_ok());
+        let unknown = users.authenticate("carol", "", client).await;
+        assert!(matches!(unknown, Err(AuthError::WrongCredentials { .. })));
+        assert!(Users::default().is_empty());
+        // keyed: same login, same key within the process; unrelated to the plain digest
+        let key = verified_key("alice", "alice pw").unwrap();
+        assert_eq!(verified_key("alice", "alice pw"), Some(key));
+        assert_ne!(verified_key("alice", "alice pw2"), Some(key));
+        let unkeyed: [u8; 32] = sha2::Sha256::digest(b"alice\0alice pw").into();
+        assert_ne!(key, unkeyed);
+
+        // no timers pending: the paused clock only moves when advanced
+        tokio::time::pause();
+        config.max_auth_failures_per_minute = 2;
+        let users = Users::from_config(&config).unwrap();
+        assert!(users.authenticate("alice", "alice pw", client).await.is_ok());
+        assert!(users.authenticate("alice", "wrong", client).await.is_err());
+        assert!(users.authenticate("carol", "wrong", client).await.is_err());
+        // refused unchecked, even with the right password; other addresses still checked
+        let refused = users.authenticate("bob", "bob pw", client).await;
+        assert!(matches!(refused, Err(AuthError::TooManyFailures { .. })));
+        assert!(users.authenticate("bob", "bob pw", "192.0.2.2".parse().unwrap()).await.is_ok());
+        // verified logins don't count
+        assert!(users.authenticate("alice", "alice pw", client).await.is_ok());
+        tokio::time::advance(FAILURE_WINDOW).await;
+        assert!(users.authenticate("bob", "bob pw", client).await.is_ok());
+
+        config.users.push(user("bob", PasswordHash::new("x", 10).unwrap().to_string()));
+        assert!(Users::from_config(&config).is_err());
+        config.users = vec![user("alice", "plain text".to_string())];
+        assert!(Users::from_config(&config).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,10 @@
+use crate::error::BackendError;
+use crate::pool::PoolHandle;
+use futures::future::BoxFuture;
+
+// Source of socks endpoints for the pool. Backend registers its instances thru PoolHandle
+// and keeps their status up to date; balancing is done by the pool for all backends alike.
+pub trait Backend: Send + Sync {
+    fn kind(&self) -> &'static str;
+    fn start(&self, pool: PoolHandle) -> BoxFuture<'_, Result<(), BackendError>>;
+}
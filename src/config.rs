@@ -1,6 +1,8 @@
+use crate::error::ConfigFileError;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::error::Error;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum LogLevelConfig {
@@ -28,25 +30,306 @@ impl Default for LogConfig {
     }
 }
 
+// what to do with tor still holding an instance data dir (e.g. left from previous run)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RunningInstancePolicyConfig {
+    #[default]
+    Fail,
+    Kill,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TorDataDirsConfig {
     pub path: String,
     pub clear: bool,
+    // files removed from each instance dir on start (when not cleared)
+    #[serde(default)]
+    pub drop_files: Vec<String>,
+    // guards sampled earlier are removed from instance state on start
+    #[serde(default)]
+    pub guard_max_age_days: Option<u32>,
+    // keep the freshest consensus in <path>/seed and copy it to instances without one
+    #[serde(default)]
+    pub seed: bool,
+    #[serde(default)]
+    pub on_running_instance: RunningInstancePolicyConfig,
     #[serde(skip_serializing, skip_deserializing)]
     pub full_path: String,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CredentialsConfig {
+    pub username: String,
+    pub password: String,
+}
+
+fn default_check_interval_ms() -> u64 {
+    30_000
+}
+
+fn default_check_timeout_ms() -> u64 {
+    5_000
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UpstreamConfig {
+    pub addr: String,
+    #[serde(default)]
+    pub credentials: Option<CredentialsConfig>,
+    // health check (connect and socks5 greeting) period, 0 disables
+    #[serde(default = "default_check_interval_ms")]
+    pub check_interval_ms: u64,
+    #[serde(default = "default_check_timeout_ms")]
+    pub check_timeout_ms: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PortModeConfig {
+    // start_port..start_port + port_count
+    #[default]
+    Fixed,
+    // any free local ports
+    Auto,
+    // unix domain socket in each instance data dir, no ports
+    Unix,
+}
+
+// torrc check at start: errors (conflicts with dyn_tor) fail it unless Off
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TorrcCheckConfig {
+    Off,
+    // warnings are logged
+    #[default]
+    Warn,
+    // warnings fail the check too
+    Strict,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CgroupConfig {
+    // cgroup v2 directory, each instance gets its own child group in it
+    pub path: String,
+    #[serde(default)]
+    pub memory_max_mb: Option<u64>,
+    // percent of one cpu
+    #[serde(default)]
+    pub cpu_max_percent: Option<u32>,
+}
+
+// applied to every tor child
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct TorLimitsConfig {
+    #[serde(default)]
+    pub address_space_mb: Option<u64>,
+    #[serde(default)]
+    pub open_files: Option<u64>,
+    #[serde(default)]
+    pub cpu_time_secs: Option<u64>,
+    #[serde(default)]
+    pub nice: Option<i32>,
+    #[serde(default)]
+    pub cgroup: Option<CgroupConfig>,
+}
+
+fn default_verify() -> bool {
+    true
+}
+
+fn default_early_exit_ms() -> u64 {
+    5000
+}
+
+fn default_max_early_exit_ratio() -> f64 {
+    0.5
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TorConfig {
     pub path: String,
+    // may be empty when torrc_template is set
     pub torrc: String,
+    // inline torrc used instead of the torrc file
+    #[serde(default)]
+    pub torrc_template: Option<String>,
+    #[serde(default)]
+    pub torrc_check: TorrcCheckConfig,
     pub data_dirs: TorDataDirsConfig,
     pub start_port: u16,
     pub port_count: u16,
+    #[serde(default)]
+    pub port_mode: PortModeConfig,
+    // {control_port} in torrc: control_start_port + index, os picked ports in Auto mode
+    #[serde(default)]
+    pub control_start_port: Option<u16>,
+    // {group} in torrc: instances are assigned to groups round robin
+    #[serde(default)]
+    pub groups: Vec<String>,
+    #[serde(default)]
+    pub credentials: Option<CredentialsConfig>,
+    #[serde(default)]
+    pub limits: TorLimitsConfig,
+    // instances bootstrapping at the same time, all if not set
+    #[serde(default)]
+    pub startup_concurrency: Option<usize>,
+    // pause before each instance spawn
+    #[serde(default)]
+    pub startup_delay_ms: u64,
+    // run 'tor --version' and 'tor --verify-config' before spawning instances
+    #[serde(default = "default_verify")]
+    pub verify: bool,
+    // e.g. "0.4.5.0"
+    #[serde(default)]
+    pub min_version: Option<String>,
+    // an instance exiting within this time after spawn failed to start, 0 disables the check
+    #[serde(default = "default_early_exit_ms")]
+    pub early_exit_ms: u64,
+    // start fails when a larger fraction of the first spawned batch exits early
+    #[serde(default = "default_max_early_exit_ratio")]
+    pub max_early_exit_ratio: f64,
+    // appended to tor arguments, same placeholders as torrc
+    #[serde(default)]
+    pub extra_args: Vec<String>,
+    // added to tor environment
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    // tor gets only `env`
+    #[serde(default)]
+    pub clear_env: bool,
+    // tor working directory, dyn_tor's one if not set
+    #[serde(default)]
+    pub working_dir: Option<String>,
+    // command tor is run with, e.g. ["nice", "-n", "10"]: tor path and arguments are appended
+    #[serde(default)]
+    pub wrapper: Vec<String>,
     #[serde(skip_serializing, skip_deserializing)]
     pub full_path: String,
     #[serde(skip_serializing, skip_deserializing)]
     pub torrc_full_path: String,
+    #[serde(skip_serializing, skip_deserializing)]
+    pub working_dir_full_path: String,
+}
+
+// clients over a connection limit
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LimitPolicyConfig {
+    // closed right away
+    #[default]
+    Reject,
+    // wait until a connection is closed
+    Queue,
+}
+
+fn default_connect_timeout_ms() -> u64 {
+    10_000
+}
+
+// tor's SocksTimeout
+fn default_handshake_timeout_ms() -> u64 {
+    120_000
+}
+
+fn default_idle_timeout_secs() -> u64 {
+    600
+}
+
+fn default_splice() -> bool {
+    true
+}
+
+fn default_max_auth_failures_per_minute() -> u32 {
+    10
+}
+
+// client allowed to use the proxy, socks5 username/password
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UserConfig {
+    pub username: String,
+    // from `dyn_tor hash-password`: pbkdf2-sha256$<iterations>$<salt>$<hash>
+    pub password_hash: String,
+    // tor.groups the user's connections go to, any instance if empty
+    #[serde(default)]
+    pub groups: Vec<String>,
+    // connections of the user at once
+    #[serde(default)]
+    pub max_connections: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProxyConfig {
+    // clients served at once, unlimited if not set
+    #[serde(default)]
+    pub max_connections: Option<usize>,
+    #[serde(default)]
+    pub max_connections_per_ip: Option<usize>,
+    #[serde(default)]
+    pub on_limit: LimitPolicyConfig,
+    // RLIMIT_NOFILE raised to this at start
+    #[serde(default)]
+    pub open_files: Option<u64>,
+    // timeouts, 0 disables: connecting to an instance
+    #[serde(default = "default_connect_timeout_ms")]
+    pub connect_timeout_ms: u64,
+    // socks exchange up to the reply to the client request (circuit built)
+    #[serde(default = "default_handshake_timeout_ms")]
+    pub handshake_timeout_ms: u64,
+    // no bytes in either direction
+    #[serde(default = "default_idle_timeout_secs")]
+    pub idle_timeout_secs: u64,
+    #[serde(default)]
+    pub max_lifetime_secs: u64,
+    // Linux: relay with splice(2) after the handshake instead of copying through buffers
+    #[serde(default = "default_splice")]
+    pub splice: bool,
+    // client address ranges (CIDR), checked on accept; reloaded on SIGHUP
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+    // with users (here or in users_file) clients must authenticate; reloaded on SIGHUP
+    #[serde(default)]
+    pub users: Vec<UserConfig>,
+    // json list of users
+    #[serde(default)]
+    pub users_file: Option<String>,
+    // failed logins per client address, further attempts refused without checking; 0: unlimited
+    #[serde(default = "default_max_auth_failures_per_minute")]
+    pub max_auth_failures_per_minute: u32,
+    #[serde(skip_serializing, skip_deserializing)]
+    pub users_file_full_path: String,
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        Self {
+            max_connections: None,
+            max_connections_per_ip: None,
+            on_limit: Default::default(),
+            open_files: None,
+            connect_timeout_ms: default_connect_timeout_ms(),
+            handshake_timeout_ms: default_handshake_timeout_ms(),
+            idle_timeout_secs: default_idle_timeout_secs(),
+            max_lifetime_secs: 0,
+            splice: default_splice(),
+            allow: vec![],
+            deny: vec![],
+            users: vec![],
+            users_file: None,
+            max_auth_failures_per_minute: default_max_auth_failures_per_minute(),
+            users_file_full_path: String::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SystemdConfig {
+    // READY=1 is sent when this many instances have bootstrapped (capped by instance count)
+    pub min_ready: usize,
+}
+
+impl Default for SystemdConfig {
+    fn default() -> Self {
+        Self { min_ready: 1 }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -54,7 +337,18 @@ pub struct AppConfig {
     pub tor: TorConfig,
     pub listen_addr: String,
     #[serde(default)]
+    pub proxy: ProxyConfig,
+    #[serde(default)]
     pub log: LogConfig,
+    #[serde(default)]
+    pub upstreams: Vec<UpstreamConfig>,
+    #[serde(default)]
+    pub systemd: SystemdConfig,
+    // default: config file path with '.pid' extension
+    #[serde(default)]
+    pub pid_file: Option<String>,
+    #[serde(skip_serializing, skip_deserializing)]
+    pub pid_file_full_path: String,
     // #[serde(skip_serializing, skip_deserializing)]
     // pub tor_full_path: String,
     // #[serde(skip_serializing, skip_deserializing)]
@@ -86,21 +380,196 @@ pub fn get_config_file_path(force_near_binary: bool) -> Result<(PathBuf, bool),
     }
 }
 
-pub fn load_config() -> Result<(AppConfig, PathBuf), Box<dyn Error>> {
+pub const INCLUDE_KEY: &str = "include";
+
+pub fn read_config_value(file_path: &Path) -> Result<serde_json::Value, ConfigFileError> {
+    let file_path_str = file_path.to_str().unwrap().to_string();
+    let data = std::fs::read(file_path).map_err(|e| ConfigFileError::Read {
+        path: file_path_str.clone(),
+        error: e.to_string(),
+    })?;
+    serde_json::from_slice(&data).map_err(|e| ConfigFileError::Parse {
+        path: file_path_str,
+        error: e.to_string(),
+    })
+}
+
+// objects are merged key by key, everything else (arrays too) is replaced by overlay
+pub fn merge_config_values(base: &mut serde_json::Value, overlay: serde_json::Value) {
+    use serde_json::Value;
+    match (base, overlay) {
+        (Value::Object(base), Value::Object(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(x) => merge_config_values(x, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+pub fn load_config() -> Result<(serde_json::Value, PathBuf), Box<dyn Error>> {
     let (file_path, checked) = get_config_file_path(false)?;
     let file_path_str = file_path.to_str().unwrap().to_string();
     if checked || (file_path.exists() && file_path.is_file()) {
         println!("config file: '{}'", file_path_str);
-        let data = std::fs::read(file_path.clone())
-            .expect(&("Unable to read config file: ".to_owned() + &file_path_str));
-        let res: AppConfig = serde_json::from_slice(&data)
-            .expect(&("Unable to parse config file: ".to_owned() + &file_path_str));
+        let res = read_config_value(&file_path)?;
         Ok((res, file_path))
     } else {
         panic!("config file '{}' does not exists", &file_path_str)
     }
 }
 
+pub fn config_from_value(value: serde_json::Value, path: &str) -> Result<AppConfig, ConfigFileError> {
+    serde_json::from_value(value).map_err(|e| ConfigFileError::Parse {
+        path: path.to_string(),
+        error: e.to_string(),
+    })
+}
+
+// Environment overrides.
+// Precedence (highest first): DYN_TOR_* environment variables, config file, built-in defaults.
+// Variable name is the field path in upper case with '__' between levels:
+//   tor.port_count -> DYN_TOR_TOR__PORT_COUNT, listen_addr -> DYN_TOR_LISTEN_ADDR
+pub const ENV_PREFIX: &str = "DYN_TOR_";
+pub const ENV_LEVEL_SEPARATOR: &str = "__";
+
+#[derive(Debug, Clone)]
+pub struct EnvOverride {
+    pub field: String,
+    pub var: String,
+}
+
+// Candidates for the new value, in order of preference. Unset optional fields are null, so
+// their type is unknown: the value as JSON (numbers, lists) first, then as a plain string
+// (paths, versions like 0.4.7.0).
+fn env_value(
+    name: &str,
+    current: &serde_json::Value,
+    value: &str,
+) -> Result<Vec<serde_json::Value>, ConfigFileError> {
+    use serde_json::Value;
+    let err = |error: String| ConfigFileError::EnvOverride {
+        name: name.to_string(),
+        error,
+    };
+    match current {
+        Value::String(_) => Ok(vec![Value::String(value.to_string())]),
+        Value::Bool(_) => value
+            .parse::<bool>()
+            .map(|x| vec![Value::Bool(x)])
+            .map_err(|e| err(e.to_string())),
+        Value::Number(_) => value
+            .parse::<serde_json::Number>()
+            .map(|x| vec![Value::Number(x)])
+            .map_err(|e| err(e.to_string())),
+        Value::Null => Ok(serde_json::from_str(value)
+            .into_iter()
+            .chain(std::iter::once(Value::String(value.to_string())))
+            .collect()),
+        _ => serde_json::from_str(value)
+            .map(|x| vec![x])
+            .map_err(|e| err(e.to_string())),
+    }
+}
+
+pub fn apply_env_overrides<I>(
+    config: AppConfig,
+    vars: I,
+) -> Result<(AppConfig, Vec<EnvOverride>), ConfigFileError>
+where
+    I: IntoIterator<Item = (String, String)>,
+{
+    let mut root = serde_json::to_value(&config).map_err(|e| ConfigFileError::EnvOverride {
+        name: ENV_PREFIX.to_string(),
+        error: e.to_string(),
+    })?;
+    let mut vars: Vec<(String, String)> = vars
+        .into_iter()
+        .filter(|(name, _)| name.starts_with(ENV_PREFIX))
+        .collect();
+    vars.sort();
+
+    let mut overrides = Vec::new();
+    for (name, value) in vars {
+        let path: Vec<String> = name[ENV_PREFIX.len()..]
+            .split(ENV_LEVEL_SEPARATOR)
+            .map(|x| x.to_lowercase())
+            .collect();
+        let field = path.join(".");
+        let pointer = path.iter().map(|x| "/".to_string() + x).collect::<String>();
+        let current = root.pointer(&pointer).ok_or_else(|| ConfigFileError::EnvOverride {
+            name: name.clone(),
+            error: format!("unknown config parameter '{}'", field),
+        })?;
+        let candidates = env_value(&name, current, &value)?;
+        let count = candidates.len();
+        for (i, candidate) in candidates.into_iter().enumerate() {
+            *root.pointer_mut(&pointer).unwrap() = candidate;
+            // the last one stays even if it doesn't fit: reported below
+            if i + 1 == count || serde_json::from_value::<AppConfig>(root.clone()).is_ok() {
+                break;
+            }
+        }
+        overrides.push(EnvOverride { field, var: name });
+    }
+
+    let res = serde_json::from_value(root).map_err(|e| ConfigFileError::EnvOverride {
+        name: ENV_PREFIX.to_string(),
+        error: e.to_string(),
+    })?;
+    Ok((res, overrides))
+}
+
+// Where each effective value comes from: "env <var>", "config file" or "built-in default".
+// `file_value` is the merged config files before env overrides.
+fn config_sources_impl(
+    pointer: &str,
+    value: &serde_json::Value,
+    file_value: &serde_json::Value,
+    overrides: &[EnvOverride],
+    res: &mut Vec<(String, serde_json::Value, String)>,
+) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, value) in map {
+                config_sources_impl(&(pointer.to_string() + "/" + key), value, file_value, overrides, res);
+            }
+        }
+        _ => {
+            let field = pointer[1..].replace('/', ".");
+            let source = match overrides.iter().find(|x| x.field == field) {
+                Some(x) => format!("env {}", x.var),
+                None if file_value.pointer(pointer).is_some() => "config file".to_string(),
+                None => "built-in default".to_string(),
+            };
+            res.push((field, value.clone(), source));
+        }
+    }
+}
+
+pub fn config_sources(
+    config: &AppConfig,
+    file_value: &serde_json::Value,
+    overrides: &[EnvOverride],
+) -> Vec<(String, serde_json::Value, String)> {
+    let mut res = Vec::new();
+    if let Ok(value) = serde_json::to_value(config) {
+        config_sources_impl("", &value, file_value, overrides, &mut res);
+    }
+    res
+}
+
+pub fn log_config_sources(config: &AppConfig, file_value: &serde_json::Value, overrides: &[EnvOverride]) {
+    for (field, value, source) in config_sources(config, file_value, overrides) {
+        log::debug!("{} = {} ({})", field, value, source);
+    }
+}
+
 /*
 pub fn save_config(app_config: &AppConfig) -> Result<(), Box<dyn Error>> {
     let (file_path, _) = get_config_file_path(false)?;
@@ -117,15 +586,125 @@ pub fn save_default_config() -> Result<(), Box<dyn Error>> {
             data_dirs: TorDataDirsConfig {
                 path: "./tor/data_dirs".to_string(),
                 clear: true,
+                drop_files: vec![],
+                guard_max_age_days: None,
+                seed: false,
+                on_running_instance: Default::default(),
                 full_path: "".to_string(),
             },
             start_port: 8600,
             port_count: 20,
             full_path: "".to_string(),
-            torrc_full_path: "".to_string()
+            torrc_full_path: "".to_string(),
+            extra_args: vec![],
+            env: Default::default(),
+            clear_env: false,
+            working_dir: None,
+            wrapper: vec![],
+            working_dir_full_path: "".to_string(),
+            port_mode: PortModeConfig::Fixed,
+            torrc_template: None,
+            torrc_check: Default::default(),
+            control_start_port: None,
+            groups: vec![],
+            credentials: None,
+            limits: Default::default(),
+            startup_concurrency: None,
+            startup_delay_ms: 0,
+            verify: true,
+            min_version: None,
+            early_exit_ms: 5000,
+            max_early_exit_ratio: 0.5,
         },
         listen_addr: "127.0.0.1:9051".to_string(),
+        proxy: Default::default(),
         log: Default::default(),
+        upstreams: vec![],
+        systemd: Default::default(),
+        pid_file: None,
+        pid_file_full_path: "".to_string(),
     })
 }
-*/
\ No newline at end of file
+*/
+#[cfg(test)]
+mod tests {
+    use crate::config::{apply_env_overrides, config_sources, AppConfig, LogLevelConfig};
+    use crate::error::ConfigFileError;
+
+    fn proto_config() -> AppConfig {
+        serde_json::from_str(include_str!("../configs/dyn_tor.config.proto")).unwrap()
+    }
+
+    fn vars(list: &[(&str, &str)]) -> Vec<(String, String)> {
+        list.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn check_env_overrides() {
+        let (config, overrides) = apply_env_overrides(
+            proto_config(),
+            vars(&[
+                ("DYN_TOR_TOR__PORT_COUNT", "30"),
+                ("DYN_TOR_LISTEN_ADDR", "0.0.0.0:9051"),
+                ("DYN_TOR_TOR__DATA_DIRS__CLEAR", "false"),
+                ("DYN_TOR_LOG__LEVEL", "Warn"),
+                ("PATH", "/bin"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(config.tor.port_count, 30);
+        assert_eq!(config.listen_addr, "0.0.0.0:9051");
+        assert!(!config.tor.data_dirs.clear);
+        assert!(matches!(config.log.level, LogLevelConfig::Warn));
+        assert_eq!(config.tor.start_port, 8600);
+        assert_eq!(overrides.len(), 4);
+
+        assert!(matches!(
+            apply_env_overrides(proto_config(), vars(&[("DYN_TOR_TOR__NO_SUCH", "1")])),
+            Err(ConfigFileError::EnvOverride { .. })
+        ));
+        assert!(matches!(
+            apply_env_overrides(proto_config(), vars(&[("DYN_TOR_TOR__PORT_COUNT", "x")])),
+            Err(ConfigFileError::EnvOverride { .. })
+        ));
+    }
+
+    #[test]
+    fn check_env_overrides_of_unset_fields() {
+        let (config, _) = apply_env_overrides(
+            proto_config(),
+            vars(&[
+                ("DYN_TOR_PID_FILE", "/run/dyn_tor.pid"),
+                ("DYN_TOR_TOR__MIN_VERSION", "0.4.7.0"),
+                ("DYN_TOR_PROXY__USERS_FILE", "./users.json"),
+                ("DYN_TOR_TOR__DATA_DIRS__GUARD_MAX_AGE_DAYS", "30"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(config.pid_file.as_deref(), Some("/run/dyn_tor.pid"));
+        assert_eq!(config.tor.min_version.as_deref(), Some("0.4.7.0"));
+        assert_eq!(config.proxy.users_file.as_deref(), Some("./users.json"));
+        assert_eq!(config.tor.data_dirs.guard_max_age_days, Some(30));
+
+        // valid JSON of the wrong type: taken as a string
+        let (config, _) = apply_env_overrides(proto_config(), vars(&[("DYN_TOR_TOR__MIN_VERSION", "1")])).unwrap();
+        assert_eq!(config.tor.min_version.as_deref(), Some("1"));
+        assert!(matches!(
+            apply_env_overrides(proto_config(), vars(&[("DYN_TOR_TOR__DATA_DIRS__GUARD_MAX_AGE_DAYS", "x")])),
+            Err(ConfigFileError::EnvOverride { .. })
+        ));
+    }
+
+    #[test]
+    fn check_config_sources() {
+        let file_value = serde_json::from_str(include_str!("../configs/dyn_tor.config.proto")).unwrap();
+        let (config, overrides) = apply_env_overrides(proto_config(), vars(&[("DYN_TOR_TOR__PORT_COUNT", "30")])).unwrap();
+        let sources = config_sources(&config, &file_value, &overrides);
+        let source = |field: &str| sources.iter().find(|x| x.0 == field).unwrap().2.clone();
+        assert_eq!(source("listen_addr"), "config file");
+        assert_eq!(source("tor.port_count"), "env DYN_TOR_TOR__PORT_COUNT");
+        assert_eq!(source("tor.data_dirs.seed"), "built-in default");
+    }
+}
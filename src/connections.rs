@@ -0,0 +1,232 @@
+use crate::config::{LimitPolicyConfig, ProxyConfig};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitReached {
+    Total,
+    PerIp,
+    PerUser,
+}
+
+impl std::fmt::Display for LimitReached {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LimitReached::Total => write!(f, "max_connections"),
+            LimitReached::PerIp => write!(f, "max_connections_per_ip"),
+            LimitReached::PerUser => write!(f, "user max_connections"),
+        }
+    }
+}
+
+type PerIp = Arc<Mutex<HashMap<IpAddr, Arc<Semaphore>>>>;
+
+// Global and per client ip connection limits; a client holds its ConnectionPermit while served.
+pub struct ConnectionLimits {
+    policy: LimitPolicyConfig,
+    // longest a client stays queued (handshake timeout): queued clients hold their sockets
+    queue_timeout: Option<Duration>,
+    total: Option<Arc<Semaphore>>,
+    per_ip_max: Option<usize>,
+    per_ip: PerIp,
+}
+
+pub struct ConnectionPermit {
+    _total: Option<OwnedSemaphorePermit>,
+    _per_ip: Option<IpPermit>,
+}
+
+struct IpPermit {
+    ip: IpAddr,
+    semaphore: Arc<Semaphore>,
+    permit: Option<OwnedSemaphorePermit>,
+    per_ip: PerIp,
+}
+
+impl Drop for IpPermit {
+    fn drop(&mut self) {
+        let mut per_ip = self.per_ip.lock().unwrap();
+        self.permit.take();
+        // nobody else holds or waits for this ip: forget it
+        if Arc::strong_count(&self.semaphore) == 2 {
+            per_ip.remove(&self.ip);
+        }
+    }
+}
+
+impl ConnectionLimits {
+    pub fn new(config: &ProxyConfig) -> Self {
+        Self {
+            policy: config.on_limit,
+            queue_timeout: (config.handshake_timeout_ms > 0).then(|| Duration::from_millis(config.handshake_timeout_ms)),
+            total: config.max_connections.map(|x| Arc::new(Semaphore::new(x))),
+            per_ip_max: config.max_connections_per_ip,
+            per_ip: Default::default(),
+        }
+    }
+
+    // None when the limit is reached (Reject) or still reached at the deadline (Queue)
+    async fn wait(&self, semaphore: Arc<Semaphore>, deadline: Option<Instant>) -> Option<OwnedSemaphorePermit> {
+        match (self.policy, deadline) {
+            (LimitPolicyConfig::Reject, _) => semaphore.try_acquire_owned().ok(),
+            (LimitPolicyConfig::Queue, Some(deadline)) => {
+                tokio::time::timeout_at(deadline, semaphore.acquire_owned()).await.ok()?.ok()
+            }
+            (LimitPolicyConfig::Queue, None) => semaphore.acquire_owned().await.ok(),
+        }
+    }
+
+    async fn acquire_ip(&self, ip: IpAddr, max: usize, deadline: Option<Instant>) -> Result<IpPermit, LimitReached> {
+        let semaphore = self
+            .per_ip
+            .lock()
+            .unwrap()
+            .entry(ip)
+            .or_insert_with(|| Arc::new(Semaphore::new(max)))
+            .clone();
+        // dropped on failure too, removing the ip entry if unused
+        let mut res = IpPermit {
+            ip,
+            semaphore,
+            permit: None,
+            per_ip: self.per_ip.clone(),
+        };
+        let semaphore = res.semaphore.clone();
+        res.permit = Some(self.wait(semaphore, deadline).await.ok_or(LimitReached::PerIp)?);
+        Ok(res)
+    }
+
+    // Per ip limit is taken first, so clients queued for their ip don't hold global slots.
+    // Queued clients share one deadline for both.
+    pub async fn acquire(&self, ip: IpAddr) -> Result<ConnectionPermit, LimitReached> {
+        let deadline = self.queue_timeout.map(|x| Instant::now() + x);
+        let per_ip = match self.per_ip_max {
+            Some(max) => Some(self.acquire_ip(ip, max, deadline).await?),
+            None => None,
+        };
+        let total = match &self.total {
+            Some(total) => Some(self.wait(total.clone(), deadline).await.ok_or(LimitReached::Total)?),
+            None => None,
+        };
+        Ok(ConnectionPermit {
+            _total: total,
+            _per_ip: per_ip,
+        })
+    }
+
+    // client ips with connections being served or queued
+    #[cfg(test)]
+    fn tracked_ips(&self) -> usize {
+        self.per_ip.lock().unwrap().len()
+    }
+}
+
+type Active = Arc<Mutex<HashMap<String, usize>>>;
+
+// Connections of authenticated users. The limit comes with the user on every acquire, so
+// counts survive users being reloaded. Over the limit a client is always rejected.
+#[derive(Default)]
+pub struct UserConnections {
+    active: Active,
+}
+
+pub struct UserPermit {
+    username: String,
+    active: Active,
+}
+
+impl Drop for UserPermit {
+    fn drop(&mut self) {
+        let mut active = self.active.lock().unwrap();
+        if let Some(count) = active.get_mut(&self.username) {
+            *count -= 1;
+            if *count == 0 {
+                active.remove(&self.username);
+            }
+        }
+    }
+}
+
+impl UserConnections {
+    pub fn acquire(&self, username: &str, max: Option<usize>) -> Result<UserPermit, LimitReached> {
+        let mut active = self.active.lock().unwrap();
+        let count = active.get(username).copied().unwrap_or_default();
+        if max.is_some_and(|max| count >= max) {
+            return Err(LimitReached::PerUser);
+        }
+        active.insert(username.to_string(), count + 1);
+        Ok(UserPermit {
+            username: username.to_string(),
+            active: self.active.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{LimitPolicyConfig, ProxyConfig};
+    use crate::connections::{ConnectionLimits, LimitReached, UserConnections};
+    use std::net::IpAddr;
+    use std::time::Duration;
+    use tokio::time::Instant;
+
+    #[tokio::test]
+    async fn check_connection_limits() {
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let b: IpAddr = "10.0.0.2".parse().unwrap();
+        let mut config = ProxyConfig {
+            max_connections: Some(3),
+            max_connections_per_ip: Some(2),
+            ..Default::default()
+        };
+        let limits = ConnectionLimits::new(&config);
+        let a1 = limits.acquire(a).await.unwrap();
+        let _a2 = limits.acquire(a).await.unwrap();
+        assert_eq!(limits.acquire(a).await.err(), Some(LimitReached::PerIp));
+        let _b1 = limits.acquire(b).await.unwrap();
+        assert_eq!(limits.acquire(b).await.err(), Some(LimitReached::Total));
+        drop(a1);
+        assert!(limits.acquire(b).await.is_ok());
+        assert_eq!(limits.tracked_ips(), 2);
+
+        config.on_limit = LimitPolicyConfig::Queue;
+        config.handshake_timeout_ms = 0;
+        let limits = ConnectionLimits::new(&config);
+        let a1 = limits.acquire(a).await.unwrap();
+        let a2 = limits.acquire(a).await.unwrap();
+        let queued = tokio::time::timeout(Duration::from_millis(50), limits.acquire(a)).await;
+        assert!(queued.is_err());
+        drop(a1);
+        assert!(limits.acquire(a).await.is_ok());
+        drop(a2);
+        assert_eq!(limits.tracked_ips(), 0);
+
+        // queued no longer than the handshake timeout
+        tokio::time::pause();
+        config.handshake_timeout_ms = 5000;
+        let limits = ConnectionLimits::new(&config);
+        let _a1 = limits.acquire(a).await.unwrap();
+        let _a2 = limits.acquire(a).await.unwrap();
+        let _b1 = limits.acquire(b).await.unwrap();
+        let start = Instant::now();
+        assert_eq!(limits.acquire(a).await.err(), Some(LimitReached::PerIp));
+        assert!(start.elapsed() >= Duration::from_secs(5));
+        assert_eq!(limits.acquire(b).await.err(), Some(LimitReached::Total));
+        assert_eq!(limits.tracked_ips(), 2);
+
+        let users = UserConnections::default();
+        let alice = users.acquire("alice", Some(1)).unwrap();
+        assert_eq!(users.acquire("alice", Some(1)).err(), Some(LimitReached::PerUser));
+        // limit raised by a reload
+        let _alice2 = users.acquire("alice", Some(2)).unwrap();
+        assert!(users.acquire("bob", None).is_ok());
+        assert!(users.acquire("carol", Some(0)).is_err());
+        drop(alice);
+        assert!(users.acquire("alice", Some(2)).is_ok());
+        assert_eq!(users.active.lock().unwrap().len(), 1);
+    }
+}
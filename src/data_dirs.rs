@@ -0,0 +1,542 @@
+use crate::config::{PortModeConfig, RunningInstancePolicyConfig, TorDataDirsConfig};
+use crate::error::{ClearDataDirError, TorSpawnError};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// shared read-only copy of directory caches, used to seed instances without consensus
+pub const SEED_DIR: &str = "seed";
+pub const STATE_FILE: &str = "state";
+// data dirs root belongs to dyn_tor only if it has this file
+pub const MARKER_FILE: &str = ".dyn_tor";
+pub const LOCK_FILE: &str = "lock";
+// pid of tor spawned by dyn_tor into this instance dir
+pub const CHILD_PID_FILE: &str = "dyn_tor.pid";
+const CONSENSUS_FILE: &str = "cached-microdesc-consensus";
+const CACHE_FILES: [&str; 4] = [
+    "cached-certs",
+    CONSENSUS_FILE,
+    "cached-microdescs",
+    "cached-microdescs.new",
+];
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|x| x.modified()).ok()
+}
+
+fn copy_cache_files(from: &Path, to: &Path, read_only: bool) -> std::io::Result<()> {
+    for name in CACHE_FILES {
+        let src = from.join(name);
+        if !src.is_file() {
+            continue;
+        }
+        let dst = to.join(name);
+        if dst.exists() {
+            // read-only seed files can't be overwritten in place
+            std::fs::remove_file(&dst)?;
+        }
+        std::fs::copy(&src, &dst)?;
+        set_cache_file_permissions(&dst, read_only)?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_cache_file_permissions(path: &Path, read_only: bool) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = if read_only { 0o400 } else { 0o600 };
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn set_cache_file_permissions(path: &Path, read_only: bool) -> std::io::Result<()> {
+    let mut permissions = std::fs::metadata(path)?.permissions();
+    permissions.set_readonly(read_only);
+    std::fs::set_permissions(path, permissions)
+}
+
+// Copies caches of the instance with the freshest consensus to the seed dir.
+pub fn refresh_seed(root: &str) -> std::io::Result<()> {
+    let seed = Path::new(root).join(SEED_DIR);
+    let mut best: Option<(SystemTime, PathBuf)> = None;
+    for entry in std::fs::read_dir(root)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() || entry.path() == seed {
+            continue;
+        }
+        if let Some(time) = modified(&entry.path().join(CONSENSUS_FILE)) {
+            if best.as_ref().map(|(x, _)| time > *x).unwrap_or(true) {
+                best = Some((time, entry.path()));
+            }
+        }
+    }
+    match best {
+        Some((time, dir)) if modified(&seed.join(CONSENSUS_FILE)).map(|x| time > x).unwrap_or(true) => {
+            log::debug!("refresh consensus seed from '{}'", dir.to_string_lossy());
+            std::fs::create_dir_all(&seed)?;
+            copy_cache_files(&dir, &seed, true)
+        }
+        _ => Ok(()),
+    }
+}
+
+// Instance dir as dyn_tor names it for the port mode (or the seed dir), used by tor before.
+fn is_instance_dir(entry: &std::fs::DirEntry, port_mode: PortModeConfig) -> bool {
+    let name = entry.file_name().to_string_lossy().to_string();
+    if !entry.file_type().map(|x| x.is_dir()).unwrap_or(false) {
+        return false;
+    }
+    if name == SEED_DIR {
+        return true;
+    }
+    let index = |prefix: &str| name.strip_prefix(prefix).map(|x| x.parse::<usize>().is_ok()).unwrap_or(false);
+    let named = match port_mode {
+        PortModeConfig::Fixed => name.parse::<u16>().is_ok(),
+        PortModeConfig::Auto => index("auto_"),
+        PortModeConfig::Unix => index("unix_"),
+    };
+    named && (entry.path().join(LOCK_FILE).is_file() || entry.path().join(STATE_FILE).is_file())
+}
+
+// A root without marker that is (still) empty, or only holds instance dirs of the configured
+// layout (made before markers existed), gets one; foreign dirs stay unmarked.
+pub fn needs_marker(root: &str, port_mode: PortModeConfig) -> std::io::Result<bool> {
+    if Path::new(root).join(MARKER_FILE).exists() {
+        return Ok(false);
+    }
+    let entries = std::fs::read_dir(root)?.collect::<std::io::Result<Vec<_>>>()?;
+    Ok(entries.iter().all(|x| is_instance_dir(x, port_mode)))
+}
+
+pub fn mark_root(root: &str, port_mode: PortModeConfig) -> std::io::Result<()> {
+    if needs_marker(root, port_mode)? {
+        log::info!("mark '{}' as dyn_tor data dir", root);
+        std::fs::write(Path::new(root).join(MARKER_FILE), "created by dyn_tor; its contents may be deleted on start\n")?;
+    }
+    Ok(())
+}
+
+fn same_dir(a: &Path, b: &Path) -> bool {
+    match (std::fs::canonicalize(a), std::fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+pub fn check_clear_allowed(root: &str) -> Result<(), ClearDataDirError> {
+    let path = Path::new(root);
+    let canonical = std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    if canonical.parent().is_none() {
+        return Err(ClearDataDirError::Root {
+            path: root.to_string(),
+        });
+    }
+    let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"));
+    if let Some(home) = home {
+        if same_dir(path, Path::new(&home)) {
+            return Err(ClearDataDirError::HomeDir {
+                path: root.to_string(),
+            });
+        }
+    }
+    if !path.join(MARKER_FILE).is_file() {
+        return Err(ClearDataDirError::NoMarker {
+            path: root.to_string(),
+            marker: MARKER_FILE.to_string(),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+pub(crate) mod lock {
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::io::AsRawFd;
+    use std::path::Path;
+
+    // pids holding a lock on the file, from /proc/locks (linux only); None when unavailable
+    fn lock_holders(path: &Path) -> Option<Vec<u32>> {
+        let inode = std::fs::metadata(path).ok()?.ino();
+        let locks = std::fs::read_to_string("/proc/locks").ok()?;
+        Some(
+            locks
+                .lines()
+                .filter_map(|line| {
+                    // "1: FLOCK  ADVISORY  WRITE 1234 08:01:5678 0 EOF", waiters have '->' after id
+                    let fields: Vec<&str> = line.split_whitespace().collect();
+                    if fields.len() < 6 || fields[1] == "->" {
+                        return None;
+                    }
+                    let file_inode = fields[5].rsplit(':').next()?.parse::<u64>().ok()?;
+                    if file_inode == inode {
+                        fields[4].parse().ok()
+                    } else {
+                        None
+                    }
+                })
+                .collect(),
+        )
+    }
+
+    // tor holds flock() on <data_dir>/lock while running (fcntl lock where flock is missing).
+    // Only looked at, never taken: a tor starting right now must not find it busy.
+    pub fn is_locked(path: &Path) -> std::io::Result<bool> {
+        if let Some(holders) = lock_holders(path) {
+            return Ok(!holders.is_empty());
+        }
+        // no /proc: on BSDs flock and fcntl locks are one, F_GETLK sees both
+        let file = std::fs::File::open(path)?;
+        let mut flock: libc::flock = unsafe { std::mem::zeroed() };
+        flock.l_type = libc::F_WRLCK as _;
+        flock.l_whence = libc::SEEK_SET as _;
+        if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETLK, &mut flock) } == 0 {
+            Ok(flock.l_type as libc::c_int != libc::F_UNLCK)
+        } else {
+            Err(std::io::Error::last_os_error())
+        }
+    }
+
+    pub fn holder_pid(path: &Path) -> Option<u32> {
+        lock_holders(path)?.first().copied()
+    }
+
+    pub fn signal(pid: u32, signal: libc::c_int) -> std::io::Result<()> {
+        if unsafe { libc::kill(pid as libc::pid_t, signal) } == 0 {
+            Ok(())
+        } else {
+            Err(std::io::Error::last_os_error())
+        }
+    }
+}
+
+// (instance dir, holder pid) for instance dirs locked by running tor
+#[cfg(unix)]
+pub fn find_running_instances(root: &str) -> std::io::Result<Vec<(PathBuf, Option<u32>)>> {
+    let mut res = vec![];
+    for entry in std::fs::read_dir(root)? {
+        let entry = entry?;
+        let lock_file = entry.path().join(LOCK_FILE);
+        if entry.file_type()?.is_dir() && lock_file.is_file() && lock::is_locked(&lock_file)? {
+            res.push((entry.path(), lock::holder_pid(&lock_file)));
+        }
+    }
+    Ok(res)
+}
+
+#[cfg(not(unix))]
+pub fn find_running_instances(_root: &str) -> std::io::Result<Vec<(PathBuf, Option<u32>)>> {
+    Ok(vec![])
+}
+
+#[cfg(unix)]
+fn stop_instance(dir: &Path, pid: u32) -> Result<(), ClearDataDirError> {
+    let lock_file = dir.join(LOCK_FILE);
+    let error = |e: std::io::Error| ClearDataDirError::Kill {
+        path: dir.to_string_lossy().to_string(),
+        pid,
+        error: e.to_string(),
+    };
+    for (signal, wait_ms) in [(libc::SIGTERM, 5000), (libc::SIGKILL, 2000)] {
+        lock::signal(pid, signal).map_err(error)?;
+        for _ in 0..wait_ms / 100 {
+            if !lock::is_locked(&lock_file).map_err(error)? {
+                return Ok(());
+            }
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+    }
+    Err(error(std::io::Error::new(
+        std::io::ErrorKind::TimedOut,
+        "lock is still held",
+    )))
+}
+
+#[cfg(not(unix))]
+fn stop_instance(dir: &Path, pid: u32) -> Result<(), ClearDataDirError> {
+    Err(ClearDataDirError::InstanceRunning {
+        path: dir.to_string_lossy().to_string(),
+        pid: Some(pid),
+    })
+}
+
+pub fn write_child_pid(data_dir: &str, pid: u32) -> std::io::Result<()> {
+    std::fs::write(Path::new(data_dir).join(CHILD_PID_FILE), format!("{}\n", pid))
+}
+
+pub fn remove_child_pid(data_dir: &str) {
+    let _ = std::fs::remove_file(Path::new(data_dir).join(CHILD_PID_FILE));
+}
+
+// pid may be reused by something else: only a process started with this data dir is ours
+#[cfg(target_os = "linux")]
+fn is_child_of_previous_run(pid: u32, data_dir: &Path) -> bool {
+    match std::fs::read(format!("/proc/{}/cmdline", pid)) {
+        Ok(cmdline) => cmdline
+            .split(|x| *x == 0)
+            .any(|x| x == data_dir.as_os_str().to_string_lossy().as_bytes()),
+        Err(_) => false,
+    }
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn is_child_of_previous_run(_pid: u32, _data_dir: &Path) -> bool {
+    false
+}
+
+#[cfg(unix)]
+fn is_alive(pid: u32) -> bool {
+    lock::signal(pid, 0).is_ok()
+}
+
+// Kills tor (with its process group) left by a previous dyn_tor that died without cleanup.
+#[cfg(unix)]
+pub fn reap_leftovers(root: &str) -> Result<(), ClearDataDirError> {
+    let io_error = |e: std::io::Error| ClearDataDirError::Io {
+        path: root.to_string(),
+        error: e.to_string(),
+    };
+    for entry in std::fs::read_dir(root).map_err(io_error)? {
+        let dir = entry.map_err(io_error)?.path();
+        let pid = match std::fs::read_to_string(dir.join(CHILD_PID_FILE)) {
+            Ok(pid) => pid.trim().parse::<u32>().ok(),
+            Err(_) => continue,
+        };
+        if let Some(pid) = pid.filter(|x| is_alive(*x) && is_child_of_previous_run(*x, &dir)) {
+            let path = dir.to_string_lossy().to_string();
+            log::warn!("tor pid {} of previous run still holds '{}'; killing", pid, path);
+            // spawned as group leader, see TorBackend
+            let _ = lock::signal(pid, libc::SIGKILL);
+            let _ = unsafe { libc::killpg(pid as libc::pid_t, libc::SIGKILL) };
+            let mut waited = 0;
+            while is_alive(pid) {
+                if waited >= 2000 {
+                    return Err(ClearDataDirError::Kill {
+                        path,
+                        pid,
+                        error: "still alive after SIGKILL".to_string(),
+                    });
+                }
+                std::thread::sleep(std::time::Duration::from_millis(50));
+                waited += 50;
+            }
+        }
+        let _ = std::fs::remove_file(dir.join(CHILD_PID_FILE));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn reap_leftovers(_root: &str) -> Result<(), ClearDataDirError> {
+    Ok(())
+}
+
+// tor left running (e.g. by killed dyn_tor) keeps its data dir and port; fail or stop it
+pub fn check_running_instances(config: &TorDataDirsConfig) -> Result<(), ClearDataDirError> {
+    let root = &config.full_path;
+    let running = find_running_instances(root).map_err(|e| ClearDataDirError::Io {
+        path: root.clone(),
+        error: e.to_string(),
+    })?;
+    for (dir, pid) in running {
+        let path = dir.to_string_lossy().to_string();
+        log::warn!("tor data dir '{}' is locked by running tor (pid {:?})", path, pid);
+        match (config.on_running_instance, pid) {
+            (RunningInstancePolicyConfig::Kill, Some(pid)) => {
+                log::info!("stopping tor pid {} holding '{}'", pid, path);
+                stop_instance(&dir, pid)?;
+            }
+            _ => return Err(ClearDataDirError::InstanceRunning { path, pid }),
+        }
+    }
+    Ok(())
+}
+
+// days since 1970-01-01 for proleptic gregorian date
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let yoe = year - era * 400;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+// "2022-03-01T12:00:00" -> unix time
+fn parse_tor_time(s: &str) -> Option<i64> {
+    let (date, time) = s.split_once('T')?;
+    let date: Vec<i64> = date.split('-').map(|x| x.parse().ok()).collect::<Option<_>>()?;
+    let time: Vec<i64> = time.split(':').map(|x| x.parse().ok()).collect::<Option<_>>()?;
+    match (date.as_slice(), time.as_slice()) {
+        ([y, m, d], [hh, mm, ss]) => Some(days_from_civil(*y, *m, *d) * 86400 + hh * 3600 + mm * 60 + ss),
+        _ => None,
+    }
+}
+
+// Drops 'Guard' lines of tor state sampled more than max_age_days ago.
+// Returns new state text and number of dropped guards.
+pub fn rotate_guards(state: &str, max_age_days: u32, now: SystemTime) -> (String, usize) {
+    let now = now.duration_since(UNIX_EPOCH).map(|x| x.as_secs() as i64).unwrap_or(0);
+    let max_age = max_age_days as i64 * 86400;
+    let mut dropped = 0;
+    let mut res = String::with_capacity(state.len());
+    for line in state.lines() {
+        let sampled_on = line
+            .strip_prefix("Guard ")
+            .and_then(|x| x.split(' ').find_map(|x| x.strip_prefix("sampled_on=")))
+            .and_then(parse_tor_time);
+        match sampled_on {
+            Some(time) if now - time > max_age => dropped += 1,
+            _ => {
+                res.push_str(line);
+                res.push('\n');
+            }
+        }
+    }
+    (res, dropped)
+}
+
+fn prepare_instance_impl(config: &TorDataDirsConfig, data_dir: &Path) -> std::io::Result<()> {
+    if !data_dir.exists() {
+        if !config.seed {
+            return Ok(());
+        }
+        std::fs::create_dir_all(data_dir)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(data_dir, std::fs::Permissions::from_mode(0o700))?;
+        }
+    }
+    for name in &config.drop_files {
+        let path = data_dir.join(name);
+        if path.is_file() {
+            log::debug!("drop '{}'", path.to_string_lossy());
+            std::fs::remove_file(path)?;
+        }
+    }
+    if let Some(days) = config.guard_max_age_days {
+        let path = data_dir.join(STATE_FILE);
+        if path.is_file() {
+            let (state, dropped) = rotate_guards(&std::fs::read_to_string(&path)?, days, SystemTime::now());
+            if dropped > 0 {
+                log::debug!("'{}': {} guards older than {} days dropped", path.to_string_lossy(), dropped, days);
+                std::fs::write(path, state)?;
+            }
+        }
+    }
+    if config.seed && !data_dir.join(CONSENSUS_FILE).exists() {
+        let seed = Path::new(&config.full_path).join(SEED_DIR);
+        if seed.join(CONSENSUS_FILE).is_file() {
+            log::debug!("seed '{}' from '{}'", data_dir.to_string_lossy(), seed.to_string_lossy());
+            copy_cache_files(&seed, data_dir, false)?;
+        }
+    }
+    Ok(())
+}
+
+// Called for every instance data dir before spawn.
+pub fn prepare_instance(config: &TorDataDirsConfig, data_dir: &str) -> Result<(), TorSpawnError> {
+    prepare_instance_impl(config, Path::new(data_dir)).map_err(|e| TorSpawnError::DataDir {
+        path: data_dir.to_string(),
+        error: e.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::PortModeConfig;
+    use crate::data_dirs::{check_clear_allowed, mark_root, parse_tor_time, rotate_guards, MARKER_FILE, SEED_DIR, STATE_FILE};
+    use crate::error::ClearDataDirError;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn check_rotate_guards() {
+        assert_eq!(parse_tor_time("1970-01-02T00:00:01"), Some(86401));
+        assert_eq!(parse_tor_time("2022-03-01T00:00:00"), Some(1646092800));
+
+        let state = "TorVersion Tor 0.4.6.10\n\
+            Guard in=default rsa_id=AAAA sampled_on=2022-01-01T00:00:00 listed=1\n\
+            Guard in=default rsa_id=BBBB sampled_on=2022-02-25T00:00:00 listed=1\n\
+            LastWritten 2022-03-01 00:00:00\n";
+        let now = UNIX_EPOCH + Duration::from_secs(1646092800);
+        let (res, dropped) = rotate_guards(state, 30, now);
+        assert_eq!(dropped, 1);
+        assert!(!res.contains("AAAA"));
+        assert!(res.contains("BBBB"));
+        assert!(res.contains("LastWritten"));
+    }
+
+    #[test]
+    fn check_clear_guards() {
+        assert!(matches!(check_clear_allowed("/"), Err(ClearDataDirError::Root { .. })));
+
+        let dir = std::env::temp_dir().join(format!("dyn_tor_marker_{}", std::process::id()));
+        let sub = dir.join("foreign");
+        std::fs::create_dir_all(&sub).unwrap();
+        let dir_str = dir.to_str().unwrap();
+        // not empty: not marked
+        mark_root(dir_str, PortModeConfig::Fixed).unwrap();
+        assert!(matches!(check_clear_allowed(dir_str), Err(ClearDataDirError::NoMarker { .. })));
+
+        std::fs::remove_dir(&sub).unwrap();
+        mark_root(dir_str, PortModeConfig::Fixed).unwrap();
+        assert!(check_clear_allowed(dir_str).is_ok());
+        std::fs::remove_file(dir.join(MARKER_FILE)).unwrap();
+
+        // instance dirs of an older dyn_tor: marked for their port mode only
+        std::fs::create_dir_all(dir.join("8600")).unwrap();
+        std::fs::write(dir.join("8600").join(STATE_FILE), "").unwrap();
+        std::fs::create_dir_all(dir.join(SEED_DIR)).unwrap();
+        mark_root(dir_str, PortModeConfig::Auto).unwrap();
+        assert!(check_clear_allowed(dir_str).is_err());
+        mark_root(dir_str, PortModeConfig::Fixed).unwrap();
+        assert!(check_clear_allowed(dir_str).is_ok());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_lock_detection() {
+        use crate::data_dirs::lock::{holder_pid, is_locked};
+        use std::os::unix::io::AsRawFd;
+
+        let path = std::env::temp_dir().join(format!("dyn_tor_lock_{}", std::process::id()));
+        std::fs::write(&path, "").unwrap();
+        assert!(!is_locked(&path).unwrap());
+        // flock is per open file description: another open() of the same file conflicts
+        let holder = std::fs::File::open(&path).unwrap();
+        assert_eq!(unsafe { libc::flock(holder.as_raw_fd(), libc::LOCK_EX) }, 0);
+        assert!(is_locked(&path).unwrap());
+        if std::path::Path::new("/proc/locks").exists() {
+            assert_eq!(holder_pid(&path), Some(std::process::id()));
+        }
+        drop(holder);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn check_reap_leftovers() {
+        use crate::data_dirs::{reap_leftovers, write_child_pid};
+        use std::os::unix::process::CommandExt;
+
+        let root = std::env::temp_dir().join(format!("dyn_tor_reap_{}", std::process::id()));
+        let data_dir = root.join("8600");
+        std::fs::create_dir_all(&data_dir).unwrap();
+        let mut child = std::process::Command::new("sh")
+            .args(["-c", "sleep 60; true", "tor", "--DataDirectory"])
+            .arg(&data_dir)
+            .process_group(0)
+            .spawn()
+            .unwrap();
+        write_child_pid(data_dir.to_str().unwrap(), child.id()).unwrap();
+        // let it exec, cmdline is checked
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        // leftover is not our child in real life: someone else reaps the zombie
+        let waiter = std::thread::spawn(move || child.wait().unwrap());
+
+        reap_leftovers(root.to_str().unwrap()).unwrap();
+        assert!(!waiter.join().unwrap().success());
+        assert!(!data_dir.join(crate::data_dirs::CHILD_PID_FILE).exists());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
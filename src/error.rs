@@ -4,6 +4,58 @@ pub enum TorSpawnError {
     NotFound { path: String },
     #[error("other spawn error of '{path}' : '{error}'")]
     Other { path: String, error: String },
+    #[error("tor socks ports already in use: {ports}")]
+    PortsBusy { ports: String },
+    #[error("can't allocate free port: '{error}'")]
+    NoFreePort { error: String },
+    #[error("unix socket path '{path}' is too long ({max} bytes max)")]
+    SocketPathTooLong { path: String, max: usize },
+    #[error("can't prepare data directory '{path}': '{error}'")]
+    DataDir { path: String, error: String },
+    #[error("unix socket socks ports are not supported on this platform")]
+    UnixSocketsUnsupported,
+    #[error("can't set up cgroup '{path}': '{error}'")]
+    Cgroup { path: String, error: String },
+    #[error("process limits are not supported on this platform")]
+    LimitsUnsupported,
+    #[error("tor rejected configuration '{torrc}':\n{output}")]
+    InvalidConfig { torrc: String, output: String },
+    #[error("can't get version of '{path}':\n{output}")]
+    VersionUnknown { path: String, output: String },
+    #[error(transparent)]
+    Torrc(#[from] TorrcError),
+    #[error(transparent)]
+    Config(#[from] ConfigFileError),
+    #[error("can't render '{name}': {error}")]
+    Template { name: String, error: String },
+    #[error("tor version {version} is older than required {min_version}")]
+    Version { version: String, min_version: String },
+    #[error("{failed} of {total} tor instances exited right after start:\n{details}")]
+    EarlyExits {
+        failed: usize,
+        total: usize,
+        details: String,
+    },
+}
+
+#[derive(thiserror::Error, Debug, Clone)]
+pub enum BackendError {
+    #[error(transparent)]
+    TorSpawn(#[from] TorSpawnError),
+    #[error("invalid upstream address '{addr}': '{error}'")]
+    InvalidUpstream { addr: String, error: String },
+}
+
+#[derive(thiserror::Error, Debug, Clone)]
+pub enum TorrcError {
+    #[error("can't read torrc '{path}': '{error}'")]
+    Read { path: String, error: String },
+    #[error("torrc '{path}' line {line}: {error}")]
+    Syntax { path: String, line: usize, error: String },
+    #[error("torrc include cycle: {chain}")]
+    IncludeCycle { chain: String },
+    #[error("torrc conflicts with dyn_tor:\n{issues}")]
+    Conflicts { issues: String },
 }
 
 #[derive(thiserror::Error, Debug, Clone)]
@@ -16,13 +68,48 @@ pub enum ConfigFileError {
     },
     #[error("parameter '{name}' ({description}) can not be empty")]
     EmptyParameter { name: String, description: String },
+    #[error("can't read config file '{path}': '{error}'")]
+    Read { path: String, error: String },
+    #[error("can't parse config file '{path}': '{error}'")]
+    Parse { path: String, error: String },
+    #[error("config include cycle: {chain}")]
+    IncludeCycle { chain: String },
+    #[error("can't apply environment variable '{name}': '{error}'")]
+    EnvOverride { name: String, error: String },
+    #[error("invalid user '{username}' in '{location}': {error}")]
+    User {
+        location: String,
+        username: String,
+        error: String,
+    },
+    #[error("invalid value '{value}' of config parameter '{name}': {error}")]
+    InvalidParameter {
+        name: String,
+        value: String,
+        error: String,
+    },
+    #[error("invalid address range '{value}' in '{parameter}': {error}")]
+    Cidr {
+        parameter: String,
+        value: String,
+        error: String,
+    },
 }
 
 #[derive(thiserror::Error, Debug, Clone)]
-#[error("can't clear tor data directory '{path}': '{error}'")]
-pub struct ClearDataDirError {
-    pub path: String,
-    pub error: String,
+pub enum ClearDataDirError {
+    #[error("can't clear tor data directory '{path}': '{error}'")]
+    Io { path: String, error: String },
+    #[error("refusing to clear tor data directory '{path}': it is the filesystem root")]
+    Root { path: String },
+    #[error("refusing to clear tor data directory '{path}': it is the home directory")]
+    HomeDir { path: String },
+    #[error("refusing to clear tor data directory '{path}': no '{marker}' marker file (create it if the directory belongs to dyn_tor)")]
+    NoMarker { path: String, marker: String },
+    #[error("tor data directory '{path}' is locked by running tor{}", pid.map(|x| format!(" (pid {})", x)).unwrap_or_default())]
+    InstanceRunning { path: String, pid: Option<u32> },
+    #[error("can't stop tor (pid {pid}) holding '{path}': '{error}'")]
+    Kill { path: String, pid: u32, error: String },
 }
 
 #[derive(thiserror::Error, Debug, Clone)]
@@ -31,3 +118,49 @@ pub struct CreateDataDirError {
     pub path: String,
     pub error: String,
 }
+
+#[derive(thiserror::Error, Debug, Clone)]
+pub enum ArgsError {
+    #[error("unknown argument '{arg}' (usage: dyn_tor [--dry-run | check-config [--show-effective] | hash-password])")]
+    Unknown { arg: String },
+}
+
+#[derive(thiserror::Error, Debug, Clone)]
+pub enum SocksError {
+    #[error("unsupported socks version {version}")]
+    Version { version: u8 },
+    #[error("client does not offer 'no authentication' method")]
+    NoAcceptableMethod,
+    #[error("upstream rejected username/password authentication method")]
+    UpstreamMethod,
+    #[error("upstream rejected credentials of user '{username}'")]
+    UpstreamAuth { username: String },
+    #[error("credential field longer than 255 bytes")]
+    CredentialTooLong,
+    #[error("unix socket upstreams are not supported on this platform")]
+    UnixSocketsUnsupported,
+    #[error("unsupported socks address type {atyp}")]
+    AddressType { atyp: u8 },
+    #[error("client does not offer username/password authentication")]
+    AuthRequired,
+    #[error("unsupported username/password authentication version {version}")]
+    AuthVersion { version: u8 },
+    #[error("upstream requires authentication")]
+    UpstreamNoAuth,
+}
+
+#[derive(thiserror::Error, Debug, Clone)]
+pub enum AuthError {
+    #[error("wrong credentials for user '{username}'")]
+    WrongCredentials { username: String },
+    #[error("too many failed logins from {client}")]
+    TooManyFailures { client: std::net::IpAddr },
+}
+
+#[derive(thiserror::Error, Debug, Clone)]
+pub enum PidFileError {
+    #[error("another dyn_tor{} is running: pid file '{path}' is locked", pid.map(|x| format!(" (pid {})", x)).unwrap_or_default())]
+    Locked { path: String, pid: Option<u32> },
+    #[error("can't use pid file '{path}': '{error}'")]
+    Io { path: String, error: String },
+}
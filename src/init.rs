@@ -1,5 +1,10 @@
-use crate::config::{self, AppConfig, LogLevelConfig};
+use crate::access::AccessList;
+use crate::auth::Users;
+use crate::config::{self, AppConfig, LogLevelConfig, RunningInstancePolicyConfig};
+use crate::data_dirs;
 use crate::error;
+use crate::pid_file::PidFile;
+use crate::tor_backend;
 use std::path::{Path, PathBuf};
 
 fn init_log(log_file_path: String, level: &LogLevelConfig) -> Result<log4rs::Handle, Box<dyn std::error::Error>> {
@@ -65,7 +70,7 @@ pub enum NormalizePathError {
 fn normalize_path(path: PathBuf, relative_to: PathBuf) -> Result<PathBuf, NormalizePathError> {
     assert!(relative_to.has_root());
     let mut res = relative_to;
-    for (_, part) in path.components().enumerate() {
+    for part in path.components() {
         use std::path::Component;
         match part {
             Component::Prefix(_) => panic!(),
@@ -156,12 +161,16 @@ fn check_config(config: &AppConfig) -> Result<(), error::ConfigFileError> {
     if config.tor.path.is_empty() {
         return Err(CONFIG_PARAMETERS.tor.empty_paramter_error());
     }
-    if config.tor.torrc.is_empty() {
+    if config.tor.torrc.is_empty() && config.tor.torrc_template.is_none() {
         return Err(CONFIG_PARAMETERS.torrc.empty_paramter_error());
     }
     if config.tor.data_dirs.path.is_empty() {
         return Err(CONFIG_PARAMETERS.data_dirs.empty_paramter_error());
     }
+    if let Some(min_version) = &config.tor.min_version {
+        tor_backend::parse_min_version(min_version)?;
+    }
+    AccessList::from_config(&config.proxy)?;
     Ok(())
 }
 
@@ -169,16 +178,35 @@ fn init_config(config: &mut AppConfig, relative_to: PathBuf) -> Result<(), error
     check_config(config)?;
     config.tor.full_path =
         normalize_path_in_config(&config.tor.path, "tor.path", false, relative_to.clone())?;
-    config.tor.torrc_full_path =
-        normalize_path_in_config(&config.tor.torrc, "tor.torrc", false, relative_to.clone())?;
+    if !config.tor.torrc.is_empty() {
+        config.tor.torrc_full_path =
+            normalize_path_in_config(&config.tor.torrc, "tor.torrc", false, relative_to.clone())?;
+    }
+    if let Some(working_dir) = &config.tor.working_dir {
+        config.tor.working_dir_full_path =
+            normalize_path_in_config(working_dir, "tor.working_dir", false, relative_to.clone())?;
+    }
     config.tor.data_dirs.full_path =
         normalize_path_in_config(&config.tor.data_dirs.path, "data_dirs.path", true, relative_to.clone())?;
+    if let Some(users_file) = &config.proxy.users_file {
+        config.proxy.users_file_full_path =
+            normalize_path_in_config(users_file, "proxy.users_file", false, relative_to.clone())?;
+    }
+    Users::from_config(&config.proxy)?;
     Ok(())
 }
 
 fn remove_dir_contents<P: AsRef<Path>>(path: P) -> std::io::Result<()> {
+    remove_dir_contents_except(path, &[])
+}
+
+// top level entries named in `keep` are left as is
+fn remove_dir_contents_except<P: AsRef<Path>>(path: P, keep: &[&str]) -> std::io::Result<()> {
     for entry in std::fs::read_dir(path)? {
         let entry = entry?;
+        if keep.iter().any(|x| entry.file_name() == *x) {
+            continue;
+        }
         let path = entry.path();
         if entry.file_type()?.is_dir() {
             remove_dir_contents(&path)?;
@@ -207,9 +235,112 @@ fn get_relative_to(config_file_path: PathBuf) -> PathBuf {
     relative_to
 }
 
-pub fn init() -> Result<AppConfig, Box<dyn std::error::Error>> {
-    let (mut config, config_file_path) = config::load_config()?;
-    let relative_to = get_relative_to(config_file_path);
+fn load_config_layers(
+    mut value: serde_json::Value,
+    relative_to: &Path,
+    chain: &mut Vec<String>,
+    files: &mut Vec<String>,
+) -> Result<serde_json::Value, error::ConfigFileError> {
+    let current = chain.last().cloned().unwrap_or_default();
+    let includes = match value.as_object_mut().and_then(|x| x.remove(config::INCLUDE_KEY)) {
+        None => vec![],
+        Some(serde_json::Value::Array(list)) => list,
+        Some(x) => vec![x],
+    };
+    let mut res = serde_json::Value::Object(Default::default());
+    for include in includes {
+        let include = include.as_str().ok_or_else(|| error::ConfigFileError::Parse {
+            path: current.clone(),
+            error: format!("'{}' must contain paths, got {}", config::INCLUDE_KEY, include),
+        })?;
+        let path = normalize_path_in_config(include, config::INCLUDE_KEY, false, relative_to.to_path_buf())?;
+        if chain.contains(&path) {
+            return Err(error::ConfigFileError::IncludeCycle {
+                chain: chain.join(" -> ") + " -> " + &path,
+            });
+        }
+        let layer = config::read_config_value(Path::new(&path))?;
+        files.push(path.clone());
+        chain.push(path);
+        let layer = load_config_layers(layer, relative_to, chain, files)?;
+        chain.pop();
+        config::merge_config_values(&mut res, layer);
+    }
+    config::merge_config_values(&mut res, value);
+    Ok(res)
+}
+
+struct LoadedConfig {
+    config: AppConfig,
+    config_file_path: PathBuf,
+    relative_to: PathBuf,
+    files: Vec<String>,
+    // merged config files, before env overrides
+    file_value: serde_json::Value,
+    env_overrides: Vec<config::EnvOverride>,
+}
+
+fn load() -> Result<LoadedConfig, Box<dyn std::error::Error>> {
+    let (value, config_file_path) = config::load_config()?;
+    let config_file_path_str = config_file_path.to_str().unwrap().to_string();
+    let relative_to = get_relative_to(config_file_path.clone());
+    let mut files = vec![config_file_path_str.clone()];
+    let value = load_config_layers(
+        value,
+        &relative_to,
+        &mut vec![config_file_path_str.clone()],
+        &mut files,
+    )?;
+    let config = config::config_from_value(value.clone(), &config_file_path_str)?;
+    let (config, env_overrides) = config::apply_env_overrides(config, std::env::vars())?;
+    Ok(LoadedConfig {
+        config,
+        config_file_path,
+        relative_to,
+        files,
+        file_value: value,
+        env_overrides,
+    })
+}
+
+// load and validate config (includes, env overrides, paths) without touching logs or data dirs
+pub fn check() -> Result<AppConfig, Box<dyn std::error::Error>> {
+    let LoadedConfig {
+        mut config,
+        config_file_path,
+        relative_to,
+        ..
+    } = load()?;
+    init_config(&mut config, relative_to.clone())?;
+    init_pid_file_path(&mut config, config_file_path, relative_to)?;
+    Ok(config)
+}
+
+fn init_pid_file_path(
+    config: &mut AppConfig,
+    mut config_file_path: PathBuf,
+    relative_to: PathBuf,
+) -> Result<(), error::ConfigFileError> {
+    config.pid_file_full_path = match &config.pid_file {
+        Some(path) => normalize_path_in_config(path, "pid_file", false, relative_to)?,
+        None => {
+            config_file_path.set_extension("pid");
+            config_file_path.to_str().unwrap().to_string()
+        }
+    };
+    Ok(())
+}
+
+// pid file is locked for the lifetime of returned PidFile
+pub fn init() -> Result<(AppConfig, PidFile), Box<dyn std::error::Error>> {
+    let LoadedConfig {
+        mut config,
+        config_file_path,
+        relative_to,
+        files,
+        file_value,
+        env_overrides,
+    } = load()?;
     if config.log.r#use {
         let mut exe = std::env::current_exe().unwrap();
         exe.set_extension("log");
@@ -221,7 +352,13 @@ pub fn init() -> Result<AppConfig, Box<dyn std::error::Error>> {
     }
     log::debug!("init...");
     log::debug!("relative_to: {}", relative_to.to_str().unwrap());
-    init_config(&mut config, relative_to)?;
+    log::debug!("config files: {}", files.join(", "));
+    config::log_config_sources(&config, &file_value, &env_overrides);
+    init_config(&mut config, relative_to.clone())?;
+    init_pid_file_path(&mut config, config_file_path, relative_to)?;
+    // before data dirs: another dyn_tor may be using them
+    let pid_file = PidFile::acquire(&config.pid_file_full_path)?;
+    log::debug!("pid file: {}", config.pid_file_full_path);
 
     let data_dirs_path = config.tor.data_dirs.full_path.clone();
     if std::fs::metadata(&data_dirs_path).is_err() {
@@ -231,29 +368,84 @@ pub fn init() -> Result<AppConfig, Box<dyn std::error::Error>> {
             error: e.to_string()
         })?;
     }
+    let io_error = |e: std::io::Error| error::ClearDataDirError::Io {
+        path: data_dirs_path.clone(),
+        error: e.to_string(),
+    };
+    data_dirs::mark_root(&data_dirs_path, config.tor.port_mode).map_err(io_error)?;
+    data_dirs::reap_leftovers(&data_dirs_path)?;
+    data_dirs::check_running_instances(&config.tor.data_dirs)?;
     if config.tor.data_dirs.clear {
         log::debug!("clear data dirs ('{}')...", &data_dirs_path);
-        match remove_dir_contents(&data_dirs_path) {
+        data_dirs::check_clear_allowed(&data_dirs_path)?;
+        let mut keep = vec![data_dirs::MARKER_FILE];
+        if config.tor.data_dirs.seed {
+            // instances started after clear still get the last consensus
+            keep.push(data_dirs::SEED_DIR);
+            data_dirs::refresh_seed(&data_dirs_path).map_err(io_error)?;
+        }
+        match remove_dir_contents_except(&data_dirs_path, &keep) {
             Ok(_) => {
                 log::debug!("clear done.");
             }
             Err(e) => {
                 log::debug!("clear failed: '{}'", e.to_string());
-                return Err(error::ClearDataDirError {
-                    path: data_dirs_path.clone(),
-                    error: e.to_string(),
-                }
-                .into());
+                return Err(io_error(e).into());
             }
         }
     }
     log::debug!("init done.");
-    Ok(config)
+    Ok((config, pid_file))
+}
+
+// Validates like init() but only reports what it would do: nothing is created, cleared or stopped.
+// Returns the config and notes about the planned data dir changes.
+pub fn dry_run() -> Result<(AppConfig, Vec<String>), Box<dyn std::error::Error>> {
+    let LoadedConfig {
+        mut config,
+        config_file_path,
+        relative_to,
+        ..
+    } = load()?;
+    init_config(&mut config, relative_to.clone())?;
+    init_pid_file_path(&mut config, config_file_path, relative_to)?;
+    PidFile::check(&config.pid_file_full_path)?;
+
+    let mut notes = vec![];
+    let data_dirs_path = config.tor.data_dirs.full_path.clone();
+    if std::fs::metadata(&data_dirs_path).is_err() {
+        notes.push(format!("tor data dir '{}' would be created", data_dirs_path));
+        return Ok((config, notes));
+    }
+    let io_error = |e: std::io::Error| error::ClearDataDirError::Io {
+        path: data_dirs_path.clone(),
+        error: e.to_string(),
+    };
+    for (dir, pid) in data_dirs::find_running_instances(&data_dirs_path).map_err(io_error)? {
+        let path = dir.to_string_lossy().to_string();
+        match (config.tor.data_dirs.on_running_instance, pid) {
+            (RunningInstancePolicyConfig::Kill, Some(pid)) => {
+                notes.push(format!("tor pid {} holding '{}' would be stopped", pid, path))
+            }
+            _ => return Err(error::ClearDataDirError::InstanceRunning { path, pid }.into()),
+        }
+    }
+    if config.tor.data_dirs.clear {
+        // init marks an empty root or one with only instance dirs before the check
+        if data_dirs::needs_marker(&data_dirs_path, config.tor.port_mode).map_err(io_error)? {
+            notes.push(format!("tor data dir '{}' would be marked as dyn_tor's", data_dirs_path));
+        } else {
+            data_dirs::check_clear_allowed(&data_dirs_path)?;
+        }
+        notes.push(format!("tor data dir '{}' would be cleared", data_dirs_path));
+    }
+    Ok((config, notes))
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::init::{normalize_path, NormalizePathError};
+    use crate::error::ConfigFileError;
+    use crate::init::{load_config_layers, normalize_path, NormalizePathError};
     use std::path::PathBuf;
 
     fn ok_with_path(res: Result<PathBuf, NormalizePathError>, path: &str) -> bool {
@@ -305,4 +497,29 @@ mod tests {
             Err(NormalizePathError::GoesThruRoot { .. })
         ));
     }
+
+    #[test]
+    fn check_config_includes() {
+        let dir = std::env::temp_dir().join(format!("dyn_tor_includes_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let write = |name: &str, data: &str| std::fs::write(dir.join(name), data).unwrap();
+        write("base.config", r#"{"listen_addr": "127.0.0.1:9051", "tor": {"start_port": 8600, "port_count": 20}}"#);
+        write("host.config", r#"{"include": ["./base.config"], "tor": {"port_count": 5}}"#);
+        write("cycle_a.config", r#"{"include": ["./cycle_b.config"]}"#);
+        write("cycle_b.config", r#"{"include": ["./cycle_a.config"]}"#);
+
+        let main = serde_json::json!({"include": ["./host.config"], "listen_addr": "0.0.0.0:9051"});
+        let mut files = vec![];
+        let res = load_config_layers(main, &dir, &mut vec!["main".to_string()], &mut files).unwrap();
+        assert_eq!(res["listen_addr"], "0.0.0.0:9051");
+        assert_eq!(res["tor"]["start_port"], 8600);
+        assert_eq!(res["tor"]["port_count"], 5);
+        assert!(res.get("include").is_none());
+        assert_eq!(files.len(), 2);
+
+        let main = serde_json::json!({"include": ["./cycle_a.config"]});
+        let res = load_config_layers(main, &dir, &mut vec!["main".to_string()], &mut vec![]);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(matches!(res, Err(ConfigFileError::IncludeCycle { .. })));
+    }
 }
@@ -0,0 +1,24 @@
+pub mod access;
+pub mod auth;
+pub mod backend;
+pub mod config;
+pub mod connections;
+pub mod data_dirs;
+pub mod error;
+pub mod init;
+pub mod limits;
+pub mod password;
+pub mod pid_file;
+pub mod pool;
+pub mod proxy;
+pub mod socks;
+#[cfg(target_os = "linux")]
+pub mod splice;
+#[cfg(unix)]
+pub mod systemd;
+pub mod tor_backend;
+pub mod torrc;
+pub mod upstream_backend;
+
+pub use backend::Backend;
+pub use pool::{InstanceInfo, InstanceStatus, PoolEvent, PoolHandle, SocksAddr, TorPool, TorPoolBuilder};
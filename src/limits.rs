@@ -0,0 +1,198 @@
+use crate::config::{CgroupConfig, TorLimitsConfig};
+use crate::error::TorSpawnError;
+use std::ffi::CString;
+use std::path::Path;
+
+const CPU_MAX_PERIOD_USEC: u64 = 100_000;
+
+// Limits ready to be applied between fork and exec: no allocations left to do there.
+#[derive(Debug, Clone, Default)]
+pub struct ChildLimits {
+    address_space: Option<u64>,
+    open_files: Option<u64>,
+    cpu_time: Option<u64>,
+    nice: Option<i32>,
+    cgroup_procs: Option<CString>,
+}
+
+impl ChildLimits {
+    pub fn is_empty(&self) -> bool {
+        self.address_space.is_none()
+            && self.open_files.is_none()
+            && self.cpu_time.is_none()
+            && self.nice.is_none()
+            && self.cgroup_procs.is_none()
+    }
+}
+
+fn cgroup_error(path: &Path, e: std::io::Error) -> TorSpawnError {
+    TorSpawnError::Cgroup {
+        path: path.to_string_lossy().to_string(),
+        error: e.to_string(),
+    }
+}
+
+// <cgroup.path>/instance_<index> with memory.max and cpu.max; returns its cgroup.procs
+fn prepare_cgroup(config: &CgroupConfig, index: usize) -> Result<CString, TorSpawnError> {
+    let root = Path::new(&config.path);
+    std::fs::create_dir_all(root).map_err(|e| cgroup_error(root, e))?;
+    let mut controllers = vec![];
+    if config.memory_max_mb.is_some() {
+        controllers.push("+memory");
+    }
+    if config.cpu_max_percent.is_some() {
+        controllers.push("+cpu");
+    }
+    if !controllers.is_empty() {
+        let subtree_control = root.join("cgroup.subtree_control");
+        std::fs::write(&subtree_control, controllers.join(" "))
+            .map_err(|e| cgroup_error(&subtree_control, e))?;
+    }
+
+    let group = root.join(format!("instance_{}", index));
+    std::fs::create_dir_all(&group).map_err(|e| cgroup_error(&group, e))?;
+    if let Some(mb) = config.memory_max_mb {
+        let path = group.join("memory.max");
+        std::fs::write(&path, (mb * 1024 * 1024).to_string()).map_err(|e| cgroup_error(&path, e))?;
+    }
+    if let Some(percent) = config.cpu_max_percent {
+        let path = group.join("cpu.max");
+        let quota = CPU_MAX_PERIOD_USEC * percent as u64 / 100;
+        std::fs::write(&path, format!("{} {}", quota, CPU_MAX_PERIOD_USEC))
+            .map_err(|e| cgroup_error(&path, e))?;
+    }
+    let procs = group.join("cgroup.procs");
+    CString::new(procs.to_string_lossy().as_bytes()).map_err(|e| TorSpawnError::Cgroup {
+        path: procs.to_string_lossy().to_string(),
+        error: e.to_string(),
+    })
+}
+
+pub fn prepare(config: &TorLimitsConfig, index: usize) -> Result<ChildLimits, TorSpawnError> {
+    let res = ChildLimits {
+        address_space: config.address_space_mb.map(|x| x * 1024 * 1024),
+        open_files: config.open_files,
+        cpu_time: config.cpu_time_secs,
+        nice: config.nice,
+        cgroup_procs: match &config.cgroup {
+            Some(cgroup) => Some(prepare_cgroup(cgroup, index)?),
+            None => None,
+        },
+    };
+    if !res.is_empty() && cfg!(not(unix)) {
+        return Err(TorSpawnError::LimitsUnsupported);
+    }
+    Ok(res)
+}
+
+#[cfg(unix)]
+fn set_rlimit(resource: libc::c_int, value: u64) -> std::io::Result<()> {
+    let limit = libc::rlimit {
+        rlim_cur: value as libc::rlim_t,
+        rlim_max: value as libc::rlim_t,
+    };
+    if unsafe { libc::setrlimit(resource as _, &limit) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Runs in the forked child before exec.
+#[cfg(unix)]
+pub fn apply_in_child(limits: &ChildLimits) -> std::io::Result<()> {
+    if let Some(procs) = &limits.cgroup_procs {
+        // "0" moves the writing process
+        let fd = unsafe { libc::open(procs.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let written = unsafe { libc::write(fd, b"0".as_ptr() as *const libc::c_void, 1) };
+        let e = std::io::Error::last_os_error();
+        unsafe { libc::close(fd) };
+        if written != 1 {
+            return Err(e);
+        }
+    }
+    if let Some(value) = limits.address_space {
+        set_rlimit(libc::RLIMIT_AS as _, value)?;
+    }
+    if let Some(value) = limits.open_files {
+        set_rlimit(libc::RLIMIT_NOFILE as _, value)?;
+    }
+    if let Some(value) = limits.cpu_time {
+        set_rlimit(libc::RLIMIT_CPU as _, value)?;
+    }
+    if let Some(nice) = limits.nice {
+        if unsafe { libc::setpriority(libc::PRIO_PROCESS as _, 0, nice) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+// Raises soft RLIMIT_NOFILE of this process to `value`, and the hard one if allowed.
+// Returns the resulting soft limit (may be lower than asked).
+#[cfg(unix)]
+#[allow(clippy::unnecessary_cast)] // rlim_t is not u64 everywhere
+pub fn raise_open_files(value: u64) -> std::io::Result<u64> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let value = value as libc::rlim_t;
+    if value <= limit.rlim_cur {
+        return Ok(limit.rlim_cur as u64);
+    }
+    let wanted = libc::rlimit {
+        rlim_cur: value,
+        rlim_max: value.max(limit.rlim_max),
+    };
+    if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &wanted) } == 0 {
+        return Ok(value as u64);
+    }
+    // raising the hard limit needs CAP_SYS_RESOURCE: go as far as it allows
+    let capped = libc::rlimit {
+        rlim_cur: value.min(limit.rlim_max),
+        rlim_max: limit.rlim_max,
+    };
+    if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &capped) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(capped.rlim_cur as u64)
+}
+
+#[cfg(not(unix))]
+pub fn raise_open_files(_value: u64) -> std::io::Result<u64> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "RLIMIT_NOFILE"))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use crate::config::TorLimitsConfig;
+    use crate::limits::{apply_in_child, prepare};
+    use std::os::unix::process::CommandExt;
+
+    #[test]
+    fn check_child_limits() {
+        let limits = prepare(
+            &TorLimitsConfig {
+                open_files: Some(64),
+                cpu_time_secs: Some(100),
+                nice: Some(5),
+                ..Default::default()
+            },
+            0,
+        )
+        .unwrap();
+        let mut command = std::process::Command::new("sh");
+        command.args(["-c", "ulimit -n; ulimit -t"]);
+        unsafe {
+            command.pre_exec(move || apply_in_child(&limits));
+        }
+        let output = command.output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "64\n100\n");
+    }
+}
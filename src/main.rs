@@ -1,167 +1,157 @@
-extern crate core;
-
-use futures::FutureExt;
+use dyn_tor::password::{self, PasswordHash};
+use dyn_tor::proxy::Proxy;
+use dyn_tor::tor_backend::TorBackend;
+use dyn_tor::upstream_backend::UpstreamBackend;
+use dyn_tor::{init, limits, torrc, TorPoolBuilder};
 use std::error::Error;
-use std::io::{self, Write};
-use std::io::{ErrorKind, Read};
-use std::process::{Command, Stdio};
-use std::sync::{Arc, Mutex};
-use std::{thread, time};
-use tokio::io::AsyncWriteExt;
-use tokio::net::{TcpListener, TcpStream};
-
-// todo for gui: handle relative path warnings in torrc
 
-mod config;
-mod error;
-mod init;
+#[cfg(unix)]
+use dyn_tor::{access::AccessList, auth::Users, systemd};
 
-type TStdOutData = Arc<Mutex<Vec<u8>>>;
+mod args;
 
-fn stdout_stream_to_vec<R>(mut stream: R) -> TStdOutData
-where
-    R: Read + Send + 'static,
-{
-    let out = Arc::new(Mutex::new(Vec::new()));
-    let vec = out.clone();
-    thread::Builder::new()
-        .name("stdout_stream_to_vec".into())
-        .spawn(move || loop {
-            let mut buf = [0];
-            match stream.read(&mut buf) {
-                Err(err) => {
-                    log::info!("{}] Error reading from stream: {}", line!(), err);
-                    break;
-                }
-                Ok(got) => match got {
-                    0 => break,
-                    1 => vec.lock().expect("!lock").push(buf[0]),
-                    _ => {
-                        log::info!("{}] Unexpected number of bytes: {}", line!(), got);
-                        break;
-                    }
-                },
-            }
-        })
-        .expect("!thread");
-    out
+fn check_config(show_effective: bool) -> Result<(), Box<dyn Error>> {
+    let the_config = init::check()?;
+    if show_effective {
+        println!("{}", serde_json::to_string_pretty(&the_config)?);
+    }
+    if the_config.tor.port_count > 0 {
+        let issues = torrc::check_config(&the_config.tor)?;
+        for issue in &issues {
+            println!("torrc: {}", issue);
+        }
+        torrc::enforce(&the_config.tor, &issues)?;
+    }
+    println!("config ok");
+    Ok(())
 }
 
-async fn transfer(mut inbound: TcpStream, proxy_addr: String) -> Result<(), Box<dyn Error>> {
-    let mut outbound = TcpStream::connect(proxy_addr).await?;
-
-    let (mut ri, mut wi) = inbound.split();
-    let (mut ro, mut wo) = outbound.split();
-
-    let client_to_server = async {
-        tokio::io::copy(&mut ri, &mut wo).await?;
-        wo.shutdown().await
-    };
-
-    let server_to_client = async {
-        tokio::io::copy(&mut ro, &mut wi).await?;
-        wi.shutdown().await
-    };
-
-    tokio::try_join!(client_to_server, server_to_client)?;
+fn dry_run() -> Result<(), Box<dyn Error>> {
+    let (the_config, notes) = init::dry_run()?;
+    for note in notes {
+        println!("{}", note);
+    }
+    if the_config.tor.port_count > 0 {
+        let issues = torrc::check_config(&the_config.tor)?;
+        for issue in &issues {
+            println!("torrc: {}", issue);
+        }
+        torrc::enforce(&the_config.tor, &issues)?;
+        print!("{}", TorBackend::new(the_config.tor.clone()).describe_plan()?);
+    }
+    UpstreamBackend::new(the_config.upstreams.clone()).check()?;
+    for upstream in &the_config.upstreams {
+        println!("upstream: {}", upstream.addr);
+    }
+    #[cfg(unix)]
+    let listener = systemd::listen_fds().into_iter().next();
+    #[cfg(not(unix))]
+    let listener: Option<std::net::TcpListener> = None;
+    match listener {
+        Some(listener) => println!("listen: socket passed by systemd ({:?})", listener.local_addr()),
+        None => {
+            // fails like the real start would: bad address, port in use, ...
+            std::net::TcpListener::bind(&the_config.listen_addr)?;
+            println!("listen: {}", the_config.listen_addr);
+        }
+    }
+    println!("dry run ok");
+    Ok(())
+}
 
+// reads one line: `echo -n pw | dyn_tor hash-password` or typed (echoed)
+fn hash_password() -> Result<(), Box<dyn Error>> {
+    let mut password = String::new();
+    std::io::stdin().read_line(&mut password)?;
+    let password = password.trim_end_matches(['\r', '\n']);
+    if password.is_empty() {
+        return Err("empty password".into());
+    }
+    println!("{}", PasswordHash::new(password, password::DEFAULT_ITERATIONS)?);
     Ok(())
 }
 
-async fn main_impl() -> Result<(), Box<dyn Error>> {
-    let the_config = init::init()?;
-    let ports: Vec<u16> = (the_config.tor.start_port
-        ..the_config.tor.start_port + the_config.tor.port_count)
-        .collect();
-    let tor_addrs = ports
-        .iter()
-        .map(|x| "127.0.0.1:".to_string() + &x.to_string())
-        .collect::<Vec<String>>();
-    let out_list: Result<Vec<TStdOutData>, error::TorSpawnError> = ports
-        .iter()
-        .map(|x| {
-            let res = Command::new(&the_config.tor.full_path)
-                .args(&[
-                    "-f",
-                    &the_config.tor.torrc_full_path,
-                    "--SocksPort",
-                    &x.to_string(),
-                    "--DataDirectory",
-                    &(the_config.tor.data_dirs.full_path.clone() + &x.to_string()),
-                ])
-                .stdout(Stdio::piped())
-                .stderr(Stdio::piped())
-                .spawn();
+#[cfg(unix)]
+fn reload_clients() -> Result<(AccessList, Users), Box<dyn Error>> {
+    let the_config = init::check()?;
+    Ok((AccessList::from_config(&the_config.proxy)?, Users::from_config(&the_config.proxy)?))
+}
 
-            match res {
-                Ok(mut child) => Ok(stdout_stream_to_vec(child.stdout.take().expect("!stdout."))),
-                Err(e) => Err(match e.kind() {
-                    ErrorKind::NotFound => error::TorSpawnError::NotFound {
-                        path: the_config.tor.full_path.clone(),
-                    },
-                    _ => error::TorSpawnError::Other {
-                        path: the_config.tor.full_path.clone(),
-                        error: e.to_string(),
-                    },
-                }),
+// SIGHUP: config is read again, client access lists and users are replaced; on errors the old ones stay
+#[cfg(unix)]
+async fn reload_on_hangup(proxy: Proxy) {
+    let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            log::warn!("can't handle SIGHUP: {}", e);
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        match reload_clients() {
+            Ok((access, users)) => {
+                log::info!("SIGHUP: client access lists and users reloaded");
+                proxy.set_access_list(access);
+                proxy.set_users(users);
             }
-        })
-        .collect();
-
-    match out_list {
-        Ok(out_list) => {
-            tokio::spawn(async move {
-                loop {
-                    for (i, out) in out_list.iter().enumerate() {
-                        loop {
-                            let mut vec_guard = out.lock().unwrap();
-                            let s = String::from_utf8((*vec_guard).clone());
-                            if let Ok(ss) = s {
-                                if ss.is_empty() {
-                                    break;
-                                }
-                                log::info!("{i}: {ss}");
-                                io::stdout().flush().unwrap();
-                                (*vec_guard).clear();
-                            }
-                        }
-                    }
-                    tokio::time::sleep(time::Duration::from_millis(50)).await;
-                }
-            });
-
-            let listen_addr = the_config.listen_addr.clone();
-            log::info!("Listening on: {}", listen_addr);
-            let listener = TcpListener::bind(listen_addr).await?;
-            let idx_mutex = Mutex::new(0u16);
-
-            loop {
-                match listener.accept().await {
-                    Ok((inbound, _)) => {
-                        let mut idx_guard = idx_mutex.lock().unwrap();
-                        if *idx_guard > the_config.tor.port_count - 1 {
-                            *idx_guard = 0;
-                        }
-                        let server_addr = tor_addrs[*idx_guard as usize].clone();
-                        *idx_guard += 1;
-                        std::mem::drop(idx_guard);
-
-                        let transfer = transfer(inbound, server_addr.clone()).map(|r| {
-                            if let Err(_e) = r {
-                                //                        println!("Failed to transfer; error={}", e);
-                                //                        io::stdout().flush().unwrap();
-                            }
-                        });
+            Err(e) => log::warn!("SIGHUP: can't reload config, keeping access lists and users: {}", e),
+        }
+    }
+}
 
-                        tokio::spawn(transfer);
-                    }
-                    // https://stackoverflow.com/questions/2569620/socket-accept-error-24-to-many-open-files
-                    Err(e) => log::info!("couldn't get client: {:?}", e),
-                }
-            }
+async fn main_impl() -> Result<(), Box<dyn Error>> {
+    match args::parse_args(std::env::args().skip(1))? {
+        args::RunCommand::CheckConfig { show_effective } => return check_config(show_effective),
+        args::RunCommand::DryRun => return dry_run(),
+        args::RunCommand::HashPassword => return hash_password(),
+        args::RunCommand::Run => {}
+    }
+    let (the_config, _pid_file) = init::init()?;
+    if let Some(open_files) = the_config.proxy.open_files {
+        match limits::raise_open_files(open_files) {
+            Ok(res) if res < open_files => log::warn!("open files limit raised to {} only (hard limit)", res),
+            Ok(res) => log::debug!("open files limit: {}", res),
+            Err(e) => log::warn!("can't raise open files limit: {}", e),
+        }
+    }
+    let pool = TorPoolBuilder::from_config(&the_config).build();
+    let proxy = Proxy::new(pool.clone(), &the_config.proxy)?;
+    #[cfg(unix)]
+    tokio::spawn(reload_on_hangup(proxy.clone()));
+    #[cfg(unix)]
+    let listener = systemd::listen_fds().into_iter().next();
+    #[cfg(not(unix))]
+    let listener: Option<std::net::TcpListener> = None;
+    #[cfg(unix)]
+    if let Some(notifier) = systemd::Notifier::from_env() {
+        tokio::spawn(systemd::readiness(&pool, the_config.systemd.min_ready, notifier.clone()));
+        if let Some(interval) = systemd::watchdog_interval() {
+            log::debug!("systemd watchdog every {:?}", interval);
+            let proxy = proxy.clone();
+            tokio::spawn(systemd::watchdog(notifier, interval, move || proxy.is_responsive(interval)));
         }
-        Err(e) => Err(e.into()),
     }
+    pool.start().await?;
+
+    let serve = async {
+        match listener {
+            Some(listener) => {
+                log::info!("Listening on socket passed by systemd: {:?}", listener.local_addr());
+                listener.set_nonblocking(true)?;
+                proxy.serve_listener(tokio::net::TcpListener::from_std(listener)?).await
+            }
+            None => proxy.serve(&the_config.listen_addr).await,
+        }
+    };
+    let res = tokio::select! {
+        res = serve => res,
+        _ = tokio::signal::ctrl_c() => {
+            log::info!("ctrl+c, shutting down...");
+            Ok(())
+        }
+    };
+    pool.shutdown().await;
+    res
 }
 
 #[tokio::main]
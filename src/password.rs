@@ -0,0 +1,122 @@
+use sha2::Sha256;
+use std::str::FromStr;
+use subtle::ConstantTimeEq;
+
+// Password hashes for client users: PBKDF2-HMAC-SHA256 as "pbkdf2-sha256$<iterations>$<salt hex>$<hash hex>",
+// made by `dyn_tor hash-password`.
+pub const SCHEME: &str = "pbkdf2-sha256";
+pub const DEFAULT_ITERATIONS: u32 = 100_000;
+const SALT_LEN: usize = 16;
+const HASH_LEN: usize = 32;
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn pbkdf2_sha256(password: &[u8], salt: &[u8], iterations: u32, out: &mut [u8]) {
+    pbkdf2::pbkdf2_hmac::<Sha256>(password, salt, iterations, out);
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PasswordHash {
+    iterations: u32,
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+impl PasswordHash {
+    // salt from the OS random source
+    pub fn new(password: &str, iterations: u32) -> Result<Self, getrandom::Error> {
+        let mut salt = vec![0u8; SALT_LEN];
+        getrandom::getrandom(&mut salt)?;
+        let mut hash = vec![0u8; HASH_LEN];
+        pbkdf2_sha256(password.as_bytes(), &salt, iterations, &mut hash);
+        Ok(Self { iterations, salt, hash })
+    }
+
+    // Matches no password, costs as much to check as a real hash of `iterations`:
+    // unknown usernames take as long to refuse as wrong passwords.
+    pub fn dummy(iterations: u32) -> Self {
+        Self {
+            iterations,
+            salt: vec![0; SALT_LEN],
+            hash: vec![0; HASH_LEN],
+        }
+    }
+
+    pub fn iterations(&self) -> u32 {
+        self.iterations
+    }
+
+    pub fn verify(&self, password: &str) -> bool {
+        let mut hash = vec![0u8; self.hash.len()];
+        pbkdf2_sha256(password.as_bytes(), &self.salt, self.iterations, &mut hash);
+        hash.ct_eq(&self.hash).into()
+    }
+}
+
+impl FromStr for PasswordHash {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split('$').collect();
+        let [scheme, iterations, salt, hash] = parts[..] else {
+            return Err(format!("expected {}$<iterations>$<salt>$<hash>", SCHEME));
+        };
+        if scheme != SCHEME {
+            return Err(format!("unsupported scheme '{}', expected '{}'", scheme, SCHEME));
+        }
+        let iterations = iterations
+            .parse::<u32>()
+            .ok()
+            .filter(|x| *x > 0)
+            .ok_or_else(|| format!("invalid iteration count '{}'", iterations))?;
+        let salt = from_hex(salt).ok_or("salt is not hex")?;
+        let hash = from_hex(hash).filter(|x| !x.is_empty()).ok_or("hash is not hex")?;
+        Ok(Self { iterations, salt, hash })
+    }
+}
+
+impl std::fmt::Display for PasswordHash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}${}${}${}", SCHEME, self.iterations, to_hex(&self.salt), to_hex(&self.hash))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::password::{pbkdf2_sha256, to_hex, PasswordHash};
+
+    #[test]
+    fn check_password_hash() {
+        // RFC 7914, section 11
+        let mut out = [0u8; 64];
+        pbkdf2_sha256(b"passwd", b"salt", 1, &mut out);
+        assert_eq!(
+            to_hex(&out),
+            "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc\
+             49ca9cccf179b645991664b39d77ef317c71b845b1e30bd509112041d3a19783"
+        );
+
+        let hash = PasswordHash::new("secret", 10).unwrap();
+        assert!(hash.verify("secret"));
+        assert!(!hash.verify("Secret"));
+        let parsed: PasswordHash = hash.to_string().parse().unwrap();
+        assert_eq!(parsed, hash);
+        assert_ne!(PasswordHash::new("secret", 10).unwrap(), hash);
+        assert!(!PasswordHash::dummy(10).verify(""));
+        assert!("pbkdf2-sha256$0$00$00".parse::<PasswordHash>().is_err());
+        assert!("md5$1$00$00".parse::<PasswordHash>().is_err());
+        assert!("pbkdf2-sha256$10$zz$00".parse::<PasswordHash>().is_err());
+    }
+}
@@ -0,0 +1,138 @@
+use crate::error::PidFileError;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+// Exclusively locked file with our pid; on drop it's emptied and the lock released. The file
+// itself stays: unlinking a locked file lets two instances lock two different inodes.
+#[derive(Debug)]
+pub struct PidFile {
+    path: String,
+    // holds the lock
+    file: File,
+}
+
+#[cfg(unix)]
+fn try_lock(file: &File) -> std::io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(true);
+    }
+    let e = std::io::Error::last_os_error();
+    match e.raw_os_error() {
+        Some(libc::EWOULDBLOCK) => Ok(false),
+        _ => Err(e),
+    }
+}
+
+// no advisory locks: pid file is informational only
+#[cfg(not(unix))]
+fn try_lock(_file: &File) -> std::io::Result<bool> {
+    Ok(true)
+}
+
+impl PidFile {
+    pub fn acquire(path: &str) -> Result<PidFile, PidFileError> {
+        let error = |e: std::io::Error| PidFileError::Io {
+            path: path.to_string(),
+            error: e.to_string(),
+        };
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(error)?;
+        if !try_lock(&file).map_err(error)? {
+            let mut data = String::new();
+            let _ = file.read_to_string(&mut data);
+            return Err(PidFileError::Locked {
+                path: path.to_string(),
+                pid: data.trim().parse().ok(),
+            });
+        }
+        file.set_len(0).map_err(error)?;
+        file.seek(SeekFrom::Start(0)).map_err(error)?;
+        writeln!(file, "{}", std::process::id()).map_err(error)?;
+        file.flush().map_err(error)?;
+        Ok(PidFile {
+            path: path.to_string(),
+            file,
+        })
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    // Locked error if a running dyn_tor holds the pid file. Only looks: taking the lock, even
+    // for a moment, could make a dyn_tor starting right now fail.
+    pub fn check(path: &str) -> Result<(), PidFileError> {
+        let data = match std::fs::read_to_string(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => {
+                return Err(PidFileError::Io {
+                    path: path.to_string(),
+                    error: e.to_string(),
+                })
+            }
+        };
+        let pid = data.trim().parse().ok();
+        if is_held(path, pid) {
+            return Err(PidFileError::Locked {
+                path: path.to_string(),
+                pid,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn is_held(path: &str, pid: Option<u32>) -> bool {
+    match crate::data_dirs::lock::is_locked(std::path::Path::new(path)) {
+        Ok(locked) => locked,
+        // can't see locks: is the recorded process alive
+        Err(_) => pid.is_some_and(|x| unsafe { libc::kill(x as libc::pid_t, 0) } == 0),
+    }
+}
+
+#[cfg(not(unix))]
+fn is_held(_path: &str, _pid: Option<u32>) -> bool {
+    false
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        // emptied while still locked, so nobody picks up a stale pid
+        let _ = self.file.set_len(0);
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use crate::error::PidFileError;
+    use crate::pid_file::PidFile;
+
+    #[test]
+    fn check_pid_file_lock() {
+        let path = std::env::temp_dir().join(format!("dyn_tor_{}.pid", std::process::id()));
+        let path = path.to_str().unwrap();
+        let pid_file = PidFile::acquire(path).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(path).unwrap().trim(),
+            std::process::id().to_string()
+        );
+        match PidFile::acquire(path) {
+            Err(PidFileError::Locked { pid, .. }) => assert_eq!(pid, Some(std::process::id())),
+            res => panic!("{:?}", res),
+        }
+        assert!(matches!(PidFile::check(path), Err(PidFileError::Locked { .. })));
+        drop(pid_file);
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "");
+        PidFile::check(path).unwrap();
+        drop(PidFile::acquire(path).unwrap());
+        std::fs::remove_file(path).unwrap();
+    }
+}
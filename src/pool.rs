@@ -0,0 +1,406 @@
+use crate::backend::Backend;
+use crate::config::{AppConfig, CredentialsConfig, TorConfig, UpstreamConfig};
+use crate::error::BackendError;
+use crate::tor_backend::TorBackend;
+use crate::upstream_backend::UpstreamBackend;
+use futures::Stream;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{broadcast, watch};
+use tokio::task::JoinHandle;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::StreamExt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InstanceStatus {
+    Starting,
+    Ready,
+    // failed its health check, not picked until it passes again
+    Unhealthy,
+    Exited { code: Option<i32> },
+    Stopped,
+}
+
+impl InstanceStatus {
+    pub fn is_alive(&self) -> bool {
+        matches!(self, InstanceStatus::Starting | InstanceStatus::Ready)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SocksAddr {
+    Tcp(String),
+    // unix domain socket path
+    Unix(String),
+}
+
+impl std::fmt::Display for SocksAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SocksAddr::Tcp(addr) => write!(f, "{}", addr),
+            SocksAddr::Unix(path) => write!(f, "unix:{}", path),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct InstanceInfo {
+    pub index: usize,
+    pub backend: &'static str,
+    pub socks_addr: SocksAddr,
+    pub credentials: Option<CredentialsConfig>,
+    pub data_dir: Option<String>,
+    // tor.groups entry of the instance
+    pub group: Option<String>,
+    pub pid: Option<u32>,
+    pub status: InstanceStatus,
+}
+
+#[derive(Debug, Clone)]
+pub enum PoolEvent {
+    Spawned { index: usize, pid: Option<u32> },
+    Ready { index: usize },
+    Unhealthy { index: usize },
+    Exited { index: usize, code: Option<i32> },
+    Stopped { index: usize },
+    Shutdown,
+}
+
+struct PoolInner {
+    instances: Mutex<Vec<InstanceInfo>>,
+    next: AtomicUsize,
+    events: broadcast::Sender<PoolEvent>,
+    shutdown: watch::Sender<bool>,
+    tasks: Mutex<Vec<JoinHandle<()>>>,
+}
+
+// Pool side interface for backends.
+#[derive(Clone)]
+pub struct PoolHandle {
+    inner: Arc<PoolInner>,
+}
+
+impl PoolHandle {
+    // returns index of the new instance (InstanceInfo::index is ignored)
+    pub fn add_instance(&self, mut info: InstanceInfo) -> usize {
+        let mut instances = self.inner.instances.lock().unwrap();
+        info.index = instances.len();
+        instances.push(info);
+        instances.len() - 1
+    }
+
+    pub fn send_event(&self, event: PoolEvent) {
+        log::debug!("pool event: {:?}", event);
+        // no subscribers is not an error
+        let _ = self.inner.events.send(event);
+    }
+
+    pub fn set_status(&self, index: usize, status: InstanceStatus) {
+        if let Some(instance) = self.inner.instances.lock().unwrap().get_mut(index) {
+            instance.status = status;
+        }
+    }
+
+    pub fn status(&self, index: usize) -> Option<InstanceStatus> {
+        self.inner.instances.lock().unwrap().get(index).map(|x| x.status)
+    }
+
+    // sets status only if current one is `from`
+    pub fn replace_status(&self, index: usize, from: InstanceStatus, to: InstanceStatus) -> bool {
+        match self.inner.instances.lock().unwrap().get_mut(index) {
+            Some(instance) if instance.status == from => {
+                instance.status = to;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // task is awaited by TorPool::shutdown
+    pub fn spawn_task<F>(&self, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let task = tokio::spawn(task);
+        self.inner.tasks.lock().unwrap().push(task);
+    }
+
+    pub fn shutdown_requested(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut shutdown = self.inner.shutdown.subscribe();
+        async move {
+            while !*shutdown.borrow() {
+                if shutdown.changed().await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct TorPoolBuilder {
+    backends: Vec<Arc<dyn Backend>>,
+    events_capacity: Option<usize>,
+}
+
+impl TorPoolBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // tor backend (if any instances) + static upstreams
+    pub fn from_config(config: &AppConfig) -> Self {
+        let mut res = Self::new();
+        if config.tor.port_count > 0 {
+            res = res.tor(config.tor.clone());
+        }
+        if !config.upstreams.is_empty() {
+            res = res.upstreams(config.upstreams.clone());
+        }
+        res
+    }
+
+    pub fn tor(self, config: TorConfig) -> Self {
+        self.backend(Arc::new(TorBackend::new(config)))
+    }
+
+    pub fn upstreams(self, upstreams: Vec<UpstreamConfig>) -> Self {
+        self.backend(Arc::new(UpstreamBackend::new(upstreams)))
+    }
+
+    pub fn backend(mut self, backend: Arc<dyn Backend>) -> Self {
+        self.backends.push(backend);
+        self
+    }
+
+    pub fn events_capacity(mut self, capacity: usize) -> Self {
+        self.events_capacity = Some(capacity);
+        self
+    }
+
+    pub fn build(self) -> TorPool {
+        let (events, _) = broadcast::channel(self.events_capacity.unwrap_or(64).max(1));
+        let (shutdown, _) = watch::channel(false);
+        TorPool {
+            backends: Arc::new(self.backends),
+            handle: PoolHandle {
+                inner: Arc::new(PoolInner {
+                    instances: Mutex::new(vec![]),
+                    next: AtomicUsize::new(0),
+                    events,
+                    shutdown,
+                    tasks: Mutex::new(vec![]),
+                }),
+            },
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct TorPool {
+    backends: Arc<Vec<Arc<dyn Backend>>>,
+    handle: PoolHandle,
+}
+
+impl TorPool {
+    pub fn builder() -> TorPoolBuilder {
+        TorPoolBuilder::new()
+    }
+
+    pub async fn start(&self) -> Result<(), BackendError> {
+        for backend in self.backends.iter() {
+            log::debug!("starting '{}' backend...", backend.kind());
+            if let Err(e) = backend.start(self.handle.clone()).await {
+                self.shutdown().await;
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn shutdown(&self) {
+        let inner = &self.handle.inner;
+        let _ = inner.shutdown.send(true);
+        // tasks being stopped may still add new ones
+        loop {
+            let tasks = std::mem::take(&mut *inner.tasks.lock().unwrap());
+            if tasks.is_empty() {
+                break;
+            }
+            for task in tasks {
+                let _ = task.await;
+            }
+        }
+        for instance in inner.instances.lock().unwrap().iter_mut() {
+            if instance.status.is_alive() || instance.status == InstanceStatus::Unhealthy {
+                instance.status = InstanceStatus::Stopped;
+            }
+        }
+        self.handle.send_event(PoolEvent::Shutdown);
+    }
+
+    // round robin over alive instances of all backends
+    pub fn pick_instance(&self) -> Option<InstanceInfo> {
+        self.pick_instance_in(&[])
+    }
+
+    // same, limited to instances of the given tor.groups (any if empty)
+    pub fn pick_instance_in(&self, groups: &[String]) -> Option<InstanceInfo> {
+        let inner = &self.handle.inner;
+        let instances = inner.instances.lock().unwrap();
+        for _ in 0..instances.len() {
+            let idx = inner.next.fetch_add(1, Ordering::Relaxed) % instances.len();
+            let instance = &instances[idx];
+            let in_groups = groups.is_empty() || instance.group.as_ref().is_some_and(|x| groups.contains(x));
+            if instance.status.is_alive() && in_groups {
+                return Some(instance.clone());
+            }
+        }
+        None
+    }
+
+    pub fn instance_status(&self, index: usize) -> Option<InstanceInfo> {
+        self.handle.inner.instances.lock().unwrap().get(index).cloned()
+    }
+
+    pub fn instances(&self) -> Vec<InstanceInfo> {
+        self.handle.inner.instances.lock().unwrap().clone()
+    }
+
+    pub fn events(&self) -> impl Stream<Item = PoolEvent> {
+        BroadcastStream::new(self.handle.inner.events.subscribe()).filter_map(|x| x.ok())
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use crate::config::{PortModeConfig, TorConfig, TorDataDirsConfig};
+    use crate::error::{BackendError, TorSpawnError};
+    use crate::pool::{InstanceStatus, PoolEvent, TorPool};
+    use std::os::unix::fs::PermissionsExt;
+    use tokio_stream::StreamExt;
+
+    fn fake_tor_config(name: &str, script: &str, port_count: u16) -> TorConfig {
+        let dir = std::env::temp_dir().join(format!("dyn_tor_{}_{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("tor");
+        std::fs::write(&path, script).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        TorConfig {
+            path: path.to_str().unwrap().to_string(),
+            torrc: "/dev/null".to_string(),
+            data_dirs: TorDataDirsConfig {
+                path: dir.to_str().unwrap().to_string() + "/",
+                clear: false,
+                drop_files: vec![],
+                guard_max_age_days: None,
+                seed: false,
+                on_running_instance: Default::default(),
+                full_path: "".to_string(),
+            },
+            start_port: 18600,
+            port_count,
+            full_path: "".to_string(),
+            torrc_full_path: "".to_string(),
+            extra_args: vec![],
+            env: Default::default(),
+            clear_env: false,
+            working_dir: None,
+            wrapper: vec![],
+            working_dir_full_path: "".to_string(),
+            port_mode: PortModeConfig::Fixed,
+            torrc_template: None,
+            torrc_check: Default::default(),
+            control_start_port: None,
+            groups: vec![],
+            credentials: None,
+            limits: Default::default(),
+            startup_concurrency: None,
+            startup_delay_ms: 0,
+            verify: false,
+            min_version: None,
+            early_exit_ms: 0,
+            max_early_exit_ratio: 0.5,
+        }
+    }
+
+    #[tokio::test]
+    async fn check_pool_lifecycle() {
+        let config = fake_tor_config(
+            "pool_lifecycle",
+            "#!/bin/sh\necho 'Bootstrapped 100% (done): Done'\nexec sleep 60\n",
+            3,
+        );
+        let dir = config.data_dirs.path.clone();
+        let pool = TorPool::builder().tor(config).build();
+        let mut events = pool.events();
+        pool.start().await.unwrap();
+        assert_eq!(pool.instances().len(), 3);
+
+        let mut ready = 0;
+        while ready < 3 {
+            if let Some(PoolEvent::Ready { .. }) = events.next().await {
+                ready += 1;
+            }
+        }
+        let first = pool.pick_instance().unwrap().index;
+        let second = pool.pick_instance().unwrap().index;
+        assert_ne!(first, second);
+        assert_eq!(pool.instance_status(0).unwrap().status, InstanceStatus::Ready);
+
+        pool.shutdown().await;
+        assert!(pool.instances().iter().all(|x| x.status == InstanceStatus::Stopped));
+        assert!(pool.pick_instance().is_none());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn check_startup_concurrency() {
+        let mut config = fake_tor_config(
+            "startup_concurrency",
+            "#!/bin/sh\nsleep 0.2\necho 'Bootstrapped 100% (done): Done'\nexec sleep 60\n",
+            3,
+        );
+        config.startup_concurrency = Some(1);
+        let dir = config.data_dirs.path.clone();
+        let pool = TorPool::builder().tor(config).build();
+        let mut events = pool.events();
+        pool.start().await.unwrap();
+        assert_eq!(pool.instances().len(), 1);
+
+        let mut ready = 0;
+        while ready < 3 {
+            match events.next().await {
+                Some(PoolEvent::Ready { .. }) => ready += 1,
+                // next one is spawned only after previous is ready
+                Some(PoolEvent::Spawned { index, .. }) => assert_eq!(index, ready),
+                _ => {}
+            }
+        }
+        pool.shutdown().await;
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn check_early_exit() {
+        let mut config = fake_tor_config(
+            "early_exit",
+            "#!/bin/sh\necho \"[warn] Failed to parse/validate config: Unknown option 'SocksPrt'\"\nexit 1\n",
+            2,
+        );
+        config.early_exit_ms = 5000;
+        let dir = config.data_dirs.path.clone();
+        let pool = TorPool::builder().tor(config).build();
+        match pool.start().await {
+            Err(BackendError::TorSpawn(TorSpawnError::EarlyExits { failed, total, details })) => {
+                assert_eq!((failed, total), (2, 2));
+                assert!(details.contains("exit code 1"));
+                assert!(details.contains("Unknown option 'SocksPrt'"));
+            }
+            res => panic!("{:?}", res),
+        }
+        assert!(pool.instances().iter().all(|x| !x.status.is_alive()));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,764 @@
+use crate::access::AccessList;
+use crate::auth::Users;
+use crate::config::{ProxyConfig, UserConfig};
+use crate::connections::{ConnectionLimits, UserConnections, UserPermit};
+use crate::error::{ConfigFileError, SocksError};
+use crate::pool::{InstanceInfo, SocksAddr, TorPool};
+use crate::socks;
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::Instant;
+
+// accept backoff while out of file descriptors
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+// accept loop proves it's running this often (systemd watchdog)
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+const RELAY_BUFFER_SIZE: usize = 16 * 1024;
+
+// one line per client connection
+pub const ACCESS_LOG_TARGET: &str = "dyn_tor::access";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CloseReason {
+    // either side closed the connection
+    Done,
+    // client address not allowed by proxy.allow / proxy.deny
+    Denied,
+    // connection limit reached
+    Rejected,
+    // client offers no username/password authentication or credentials are wrong
+    AuthFailed,
+    NoInstance,
+    ConnectFailed,
+    ConnectTimeout,
+    HandshakeFailed,
+    HandshakeTimeout,
+    // no bytes in either direction for idle_timeout_secs
+    IdleTimeout,
+    LifetimeExceeded,
+    RelayError,
+}
+
+impl std::fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            CloseReason::Done => "done",
+            CloseReason::Denied => "denied",
+            CloseReason::Rejected => "rejected",
+            CloseReason::AuthFailed => "auth_failed",
+            CloseReason::NoInstance => "no_instance",
+            CloseReason::ConnectFailed => "connect_failed",
+            CloseReason::ConnectTimeout => "connect_timeout",
+            CloseReason::HandshakeFailed => "handshake_failed",
+            CloseReason::HandshakeTimeout => "handshake_timeout",
+            CloseReason::IdleTimeout => "idle_timeout",
+            CloseReason::LifetimeExceeded => "lifetime_exceeded",
+            CloseReason::RelayError => "relay_error",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// closed connection counts by reason
+#[derive(Debug, Default)]
+pub struct ProxyStats {
+    closed: Mutex<HashMap<CloseReason, u64>>,
+}
+
+impl ProxyStats {
+    fn count(&self, reason: CloseReason) {
+        *self.closed.lock().unwrap().entry(reason).or_default() += 1;
+    }
+
+    pub fn closed(&self, reason: CloseReason) -> u64 {
+        self.closed.lock().unwrap().get(&reason).copied().unwrap_or_default()
+    }
+}
+
+#[derive(Debug)]
+pub struct Closed {
+    pub reason: CloseReason,
+    pub error: Option<String>,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+}
+
+impl Closed {
+    fn new(reason: CloseReason, error: Option<String>) -> Self {
+        Self {
+            reason,
+            error,
+            bytes_up: 0,
+            bytes_down: 0,
+        }
+    }
+}
+
+// zero disables a timeout
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Timeouts {
+    pub connect: Option<Duration>,
+    pub handshake: Option<Duration>,
+    pub idle: Option<Duration>,
+    pub lifetime: Option<Duration>,
+}
+
+impl Timeouts {
+    pub fn from_config(config: &ProxyConfig) -> Self {
+        let nonzero = |x: Duration| (!x.is_zero()).then_some(x);
+        Self {
+            connect: nonzero(Duration::from_millis(config.connect_timeout_ms)),
+            handshake: nonzero(Duration::from_millis(config.handshake_timeout_ms)),
+            idle: nonzero(Duration::from_secs(config.idle_timeout_secs)),
+            lifetime: nonzero(Duration::from_secs(config.max_lifetime_secs)),
+        }
+    }
+}
+
+// None when timed out
+async fn with_timeout<F: Future>(timeout: Option<Duration>, future: F) -> Option<F::Output> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, future).await.ok(),
+        None => Some(future.await),
+    }
+}
+
+async fn with_deadline<F: Future>(deadline: Option<Instant>, future: F) -> Option<F::Output> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, future).await.ok(),
+        None => Some(future.await),
+    }
+}
+
+// last time bytes went either way, in ms since start
+struct Activity {
+    start: Instant,
+    last_ms: AtomicU64,
+}
+
+impl Activity {
+    fn touch(&self) {
+        self.last_ms.store(self.start.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    async fn idle(&self, timeout: Option<Duration>) {
+        let timeout = match timeout {
+            Some(timeout) => timeout,
+            None => return futures::future::pending().await,
+        };
+        loop {
+            let deadline = self.start + Duration::from_millis(self.last_ms.load(Ordering::Relaxed)) + timeout;
+            if Instant::now() >= deadline {
+                return;
+            }
+            tokio::time::sleep_until(deadline).await;
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn counted<'a>(bytes: &'a AtomicU64, activity: &'a Activity) -> impl FnMut(usize) + 'a {
+    move |n| {
+        activity.touch();
+        bytes.fetch_add(n as u64, Ordering::Relaxed);
+    }
+}
+
+// Outbound streams: tcp or unix sockets to instances, spliceable on Linux.
+#[cfg(target_os = "linux")]
+trait RelayStream: AsyncRead + AsyncWrite + Unpin + crate::splice::SpliceStream {}
+#[cfg(not(target_os = "linux"))]
+trait RelayStream: AsyncRead + AsyncWrite + Unpin {}
+
+impl RelayStream for TcpStream {}
+#[cfg(unix)]
+impl RelayStream for tokio::net::UnixStream {}
+
+async fn copy_counted<R, W>(reader: &mut R, writer: &mut W, bytes: &AtomicU64, activity: &Activity) -> std::io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; RELAY_BUFFER_SIZE];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        activity.touch();
+        writer.write_all(&buf[..n]).await?;
+        bytes.fetch_add(n as u64, Ordering::Relaxed);
+        activity.touch();
+    }
+    writer.shutdown().await
+}
+
+async fn copy_both<S: RelayStream>(
+    mut inbound: TcpStream,
+    outbound: S,
+    splice: bool,
+    up: &AtomicU64,
+    down: &AtomicU64,
+    activity: &Activity,
+) -> std::io::Result<()> {
+    #[cfg(target_os = "linux")]
+    if splice {
+        use crate::splice::{copy, Pipe};
+        match (Pipe::new(), Pipe::new()) {
+            (Ok(pipe_up), Ok(pipe_down)) => {
+                let client_to_server = copy(&inbound, &outbound, &pipe_up, counted(up, activity));
+                let server_to_client = copy(&outbound, &inbound, &pipe_down, counted(down, activity));
+                return tokio::try_join!(client_to_server, server_to_client).map(|_| ());
+            }
+            (Err(e), _) | (_, Err(e)) => log::debug!("no pipes for splice ({}), copying through buffers", e),
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = splice;
+
+    let (mut ri, mut wi) = inbound.split();
+    let (mut ro, mut wo) = tokio::io::split(outbound);
+    let client_to_server = copy_counted(&mut ri, &mut wo, up, activity);
+    let server_to_client = copy_counted(&mut ro, &mut wi, down, activity);
+    tokio::try_join!(client_to_server, server_to_client).map(|_| ())
+}
+
+// socks handshake part left when the instance is connected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Handshake {
+    // all of it, client messages relayed; handshake timeout starts now
+    Forward,
+    // client authenticated by dyn_tor: greeting upstream, then request and reply, all within
+    // the deadline set when authentication began
+    Continue { deadline: Option<Instant> },
+}
+
+async fn relay<S: RelayStream>(
+    mut inbound: TcpStream,
+    mut outbound: S,
+    instance: &InstanceInfo,
+    timeouts: &Timeouts,
+    splice: bool,
+    handshake: Handshake,
+) -> Closed {
+    let up = AtomicU64::new(0);
+    let down = AtomicU64::new(0);
+    let relay = async {
+        let credentials = instance.credentials.as_ref();
+        let deadline = match handshake {
+            Handshake::Forward => timeouts.handshake.map(|x| Instant::now() + x),
+            Handshake::Continue { deadline } => deadline,
+        };
+        let handshake = async {
+            match handshake {
+                Handshake::Forward => socks::forward_handshake(&mut inbound, &mut outbound, credentials).await,
+                Handshake::Continue { .. } => socks::continue_handshake(&mut inbound, &mut outbound, credentials).await,
+            }
+        };
+        match with_deadline(deadline, handshake).await {
+            None => return Closed::new(CloseReason::HandshakeTimeout, None),
+            Some(Err(e)) => return Closed::new(CloseReason::HandshakeFailed, Some(e.to_string())),
+            Some(Ok(())) => {}
+        }
+
+        let activity = Activity {
+            start: Instant::now(),
+            last_ms: AtomicU64::new(0),
+        };
+        tokio::select! {
+            res = copy_both(inbound, outbound, splice, &up, &down, &activity) => match res {
+                Ok(()) => Closed::new(CloseReason::Done, None),
+                Err(e) => Closed::new(CloseReason::RelayError, Some(e.to_string())),
+            },
+            _ = activity.idle(timeouts.idle) => Closed::new(CloseReason::IdleTimeout, None),
+        }
+    };
+    let mut res = with_timeout(timeouts.lifetime, relay)
+        .await
+        .unwrap_or_else(|| Closed::new(CloseReason::LifetimeExceeded, None));
+    res.bytes_up = up.load(Ordering::Relaxed);
+    res.bytes_down = down.load(Ordering::Relaxed);
+    res
+}
+
+async fn connect<S, F>(timeout: Option<Duration>, connect: F) -> Result<S, Closed>
+where
+    F: Future<Output = std::io::Result<S>>,
+{
+    match with_timeout(timeout, connect).await {
+        None => Err(Closed::new(CloseReason::ConnectTimeout, None)),
+        Some(Err(e)) => Err(Closed::new(CloseReason::ConnectFailed, Some(e.to_string()))),
+        Some(Ok(stream)) => Ok(stream),
+    }
+}
+
+// `splice`: on Linux, relay with splice(2) once the handshake is done
+pub async fn transfer(inbound: TcpStream, instance: &InstanceInfo, timeouts: &Timeouts, splice: bool) -> Closed {
+    transfer_with(inbound, instance, timeouts, splice, Handshake::Forward).await
+}
+
+async fn transfer_with(
+    inbound: TcpStream,
+    instance: &InstanceInfo,
+    timeouts: &Timeouts,
+    splice: bool,
+    handshake: Handshake,
+) -> Closed {
+    match &instance.socks_addr {
+        SocksAddr::Tcp(addr) => match connect(timeouts.connect, TcpStream::connect(addr)).await {
+            Ok(outbound) => relay(inbound, outbound, instance, timeouts, splice, handshake).await,
+            Err(closed) => closed,
+        },
+        #[cfg(unix)]
+        SocksAddr::Unix(path) => match connect(timeouts.connect, tokio::net::UnixStream::connect(path)).await {
+            Ok(outbound) => relay(inbound, outbound, instance, timeouts, splice, handshake).await,
+            Err(closed) => closed,
+        },
+        #[cfg(not(unix))]
+        SocksAddr::Unix(_) => Closed::new(
+            CloseReason::ConnectFailed,
+            Some(crate::error::SocksError::UnixSocketsUnsupported.to_string()),
+        ),
+    }
+}
+
+// Accept failing because process or system is out of descriptors (or memory for them):
+// the pending connection stays queued, retrying right away would spin.
+#[cfg(unix)]
+fn is_resource_exhaustion(e: &std::io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(libc::EMFILE) | Some(libc::ENFILE) | Some(libc::ENOBUFS) | Some(libc::ENOMEM)
+    )
+}
+
+#[cfg(not(unix))]
+fn is_resource_exhaustion(_e: &std::io::Error) -> bool {
+    false
+}
+
+// Client greeting and username/password check: the user and its connection slot.
+async fn authenticate(
+    inbound: &mut TcpStream,
+    users: &Users,
+    connections: &UserConnections,
+    client: SocketAddr,
+) -> Result<(UserConfig, UserPermit), Closed> {
+    let credentials = match socks::read_client_auth(inbound).await {
+        Ok(credentials) => credentials,
+        Err(e) => {
+            let reason = match e.downcast_ref::<SocksError>() {
+                Some(SocksError::AuthRequired) => CloseReason::AuthFailed,
+                _ => CloseReason::HandshakeFailed,
+            };
+            return Err(Closed::new(reason, Some(e.to_string())));
+        }
+    };
+    let user = match users.authenticate(&credentials.username, &credentials.password, client.ip()).await {
+        Ok(user) => user,
+        Err(e) => {
+            let _ = socks::auth_reply(inbound, false).await;
+            return Err(Closed::new(CloseReason::AuthFailed, Some(e.to_string())));
+        }
+    };
+    let permit = match connections.acquire(&user.username, user.max_connections) {
+        Ok(permit) => permit,
+        Err(limit) => {
+            let _ = socks::auth_reply(inbound, false).await;
+            return Err(Closed::new(CloseReason::Rejected, Some(format!("{} reached", limit))));
+        }
+    };
+    if let Err(e) = socks::auth_reply(inbound, true).await {
+        return Err(Closed::new(CloseReason::HandshakeFailed, Some(e.to_string())));
+    }
+    Ok((user, permit))
+}
+
+// Front listener: clients are relayed to pool instances within connection limits.
+#[derive(Clone)]
+pub struct Proxy {
+    pool: TorPool,
+    access: Arc<RwLock<AccessList>>,
+    users: Arc<RwLock<Arc<Users>>>,
+    limits: Arc<ConnectionLimits>,
+    user_connections: Arc<UserConnections>,
+    timeouts: Timeouts,
+    splice: bool,
+    stats: Arc<ProxyStats>,
+    // last turn of the accept loop, None until serving
+    heartbeat: Arc<Mutex<Option<Instant>>>,
+}
+
+impl Proxy {
+    pub fn new(pool: TorPool, config: &ProxyConfig) -> Result<Self, ConfigFileError> {
+        Ok(Self {
+            pool,
+            access: Arc::new(RwLock::new(AccessList::from_config(config)?)),
+            users: Arc::new(RwLock::new(Arc::new(Users::from_config(config)?))),
+            limits: Arc::new(ConnectionLimits::new(config)),
+            user_connections: Default::default(),
+            timeouts: Timeouts::from_config(config),
+            splice: config.splice,
+            stats: Default::default(),
+            heartbeat: Default::default(),
+        })
+    }
+
+    // applies to clients accepted from now on
+    pub fn set_access_list(&self, access: AccessList) {
+        *self.access.write().unwrap() = access;
+    }
+
+    // clients authenticating from now on; connections of removed users are kept
+    pub fn set_users(&self, users: Users) {
+        *self.users.write().unwrap() = Arc::new(users);
+    }
+
+    pub fn stats(&self) -> &ProxyStats {
+        &self.stats
+    }
+
+    // accept loop turned within max_age (or isn't serving yet) and the pool answers;
+    // a wedged pool blocks here, so a watchdog calling this stops pinging
+    pub fn is_responsive(&self, max_age: Duration) -> bool {
+        let _ = self.pool.instances();
+        match *self.heartbeat.lock().unwrap() {
+            Some(heartbeat) => heartbeat.elapsed() <= max_age.max(HEARTBEAT_INTERVAL * 2),
+            None => true,
+        }
+    }
+
+    pub async fn serve(&self, listen_addr: &str) -> Result<(), Box<dyn std::error::Error>> {
+        log::info!("Listening on: {}", listen_addr);
+        let listener = TcpListener::bind(listen_addr).await?;
+        self.serve_listener(listener).await
+    }
+
+    pub async fn serve_listener(&self, listener: TcpListener) -> Result<(), Box<dyn std::error::Error>> {
+        let mut backoff: Option<Duration> = None;
+        let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+        loop {
+            let accepted = tokio::select! {
+                res = listener.accept() => res,
+                _ = heartbeat.tick() => {
+                    *self.heartbeat.lock().unwrap() = Some(Instant::now());
+                    continue;
+                }
+            };
+            match accepted {
+                Ok((inbound, client)) => {
+                    backoff = None;
+                    if !self.access.read().unwrap().is_allowed(client.ip()) {
+                        // shed cheaply: counted, but an access log line each could flood the log
+                        drop(inbound);
+                        self.stats.count(CloseReason::Denied);
+                        log::debug!(target: ACCESS_LOG_TARGET, "{} -> -: {}", client, CloseReason::Denied);
+                        continue;
+                    }
+                    tokio::spawn(self.clone().handle(inbound, client));
+                }
+                Err(e) if is_resource_exhaustion(&e) => {
+                    let delay = match backoff {
+                        Some(delay) => (delay * 2).min(MAX_ACCEPT_BACKOFF),
+                        None => {
+                            log::warn!("couldn't get client: {}; backing off", e);
+                            MIN_ACCEPT_BACKOFF
+                        }
+                    };
+                    backoff = Some(delay);
+                    tokio::time::sleep(delay).await;
+                }
+                Err(e) => log::info!("couldn't get client: {:?}", e),
+            }
+        }
+    }
+
+    fn closed(
+        &self,
+        client: SocketAddr,
+        user: Option<&str>,
+        instance: Option<&InstanceInfo>,
+        closed: Closed,
+        start: Instant,
+    ) {
+        self.stats.count(closed.reason);
+        let user = user.map(|x| format!(" ({})", x)).unwrap_or_default();
+        let instance = instance
+            .map(|x| format!("{} ({})", x.index, x.socks_addr))
+            .unwrap_or_else(|| "-".to_string());
+        let error = closed.error.map(|x| format!(": {}", x)).unwrap_or_default();
+        log::info!(
+            target: ACCESS_LOG_TARGET,
+            "{}{} -> {}: {}{} after {:?}, {} bytes up, {} bytes down",
+            client,
+            user,
+            instance,
+            closed.reason,
+            error,
+            start.elapsed(),
+            closed.bytes_up,
+            closed.bytes_down
+        );
+    }
+
+    async fn handle(self, mut inbound: TcpStream, client: SocketAddr) {
+        let start = Instant::now();
+        // queued (or rejected) here, not in the accept loop
+        let _permit = match self.limits.acquire(client.ip()).await {
+            Ok(permit) => permit,
+            Err(limit) => {
+                let closed = Closed::new(CloseReason::Rejected, Some(format!("{} reached", limit)));
+                return self.closed(client, None, None, closed, start);
+            }
+        };
+        let users = self.users.read().unwrap().clone();
+        if users.is_empty() {
+            let instance = match self.pool.pick_instance() {
+                Some(instance) => instance,
+                None => {
+                    log::warn!("no alive tor instances, dropping client");
+                    let closed = Closed::new(CloseReason::NoInstance, None);
+                    return self.closed(client, None, None, closed, start);
+                }
+            };
+            let closed = transfer(inbound, &instance, &self.timeouts, self.splice).await;
+            return self.closed(client, None, Some(&instance), closed, start);
+        }
+
+        // one deadline for authentication and the rest of the handshake
+        let deadline = self.timeouts.handshake.map(|x| Instant::now() + x);
+        let auth = authenticate(&mut inbound, &users, &self.user_connections, client);
+        let (user, _user_permit) = match with_deadline(deadline, auth).await {
+            None => return self.closed(client, None, None, Closed::new(CloseReason::HandshakeTimeout, None), start),
+            Some(Err(closed)) => return self.closed(client, None, None, closed, start),
+            Some(Ok(res)) => res,
+        };
+        let username = Some(user.username.as_str());
+        let instance = match self.pool.pick_instance_in(&user.groups) {
+            Some(instance) => instance,
+            None => {
+                log::warn!("no alive tor instances in groups {:?} of user '{}'", user.groups, user.username);
+                return self.closed(client, username, None, Closed::new(CloseReason::NoInstance, None), start);
+            }
+        };
+        let handshake = Handshake::Continue { deadline };
+        let closed = transfer_with(inbound, &instance, &self.timeouts, self.splice, handshake).await;
+        self.closed(client, username, Some(&instance), closed, start);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{ProxyConfig, UpstreamConfig, UserConfig};
+    use crate::password::PasswordHash;
+    use crate::pool::{InstanceInfo, InstanceStatus, SocksAddr, TorPool};
+    use crate::proxy::{connect, relay, CloseReason, Handshake, Proxy, Timeouts};
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::time::Instant;
+
+    const REQUEST: [u8; 10] = [5, 1, 0, 1, 10, 0, 0, 1, 0, 80];
+    const REPLY: [u8; 10] = [5, 0, 0, 1, 127, 0, 0, 1, 0, 80];
+
+    async fn pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        // a few bytes at a time, microseconds apart in real time: no waiting for acks
+        client.set_nodelay(true).unwrap();
+        server.set_nodelay(true).unwrap();
+        (client, server)
+    }
+
+    // The paused clock jumps to the next timer even when socket events came in meanwhile:
+    // small steps keep a loopback hop from racing the timeouts under test.
+    fn pause() {
+        tokio::time::pause();
+        tokio::spawn(async {
+            loop {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        });
+    }
+
+    fn instance() -> InstanceInfo {
+        InstanceInfo {
+            index: 0,
+            backend: "test",
+            socks_addr: SocksAddr::Tcp("127.0.0.1:1".to_string()),
+            credentials: None,
+            data_dir: None,
+            group: None,
+            pid: None,
+            status: InstanceStatus::Ready,
+        }
+    }
+
+    // socks5 server without auth: replies to the request, then reads until closed
+    async fn upstream(mut stream: TcpStream) {
+        let mut greeting = [0u8; 3];
+        stream.read_exact(&mut greeting).await.unwrap();
+        stream.write_all(&[5, 0]).await.unwrap();
+        let mut request = [0u8; 10];
+        stream.read_exact(&mut request).await.unwrap();
+        stream.write_all(&REPLY).await.unwrap();
+        let mut buf = [0u8; 64];
+        while matches!(stream.read(&mut buf).await, Ok(n) if n > 0) {}
+    }
+
+    async fn client_handshake(stream: &mut TcpStream) {
+        stream.write_all(&[5, 1, 0]).await.unwrap();
+        stream.write_all(&REQUEST).await.unwrap();
+        let mut reply = [0u8; 12];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..2], [5, 0]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn check_connect_timeout() {
+        let start = Instant::now();
+        let never = futures::future::pending::<std::io::Result<TcpStream>>();
+        let closed = connect(Some(Duration::from_secs(3)), never).await.unwrap_err();
+        assert_eq!(closed.reason, CloseReason::ConnectTimeout);
+        assert!(start.elapsed() >= Duration::from_secs(3));
+
+        let refused = async { Err::<TcpStream, _>(std::io::Error::from(std::io::ErrorKind::ConnectionRefused)) };
+        let closed = connect(Some(Duration::from_secs(3)), refused).await.unwrap_err();
+        assert_eq!(closed.reason, CloseReason::ConnectFailed);
+    }
+
+    #[tokio::test]
+    async fn check_handshake_timeout() {
+        let (_client, inbound) = pair().await;
+        let (outbound, _upstream) = pair().await;
+        pause();
+        let start = Instant::now();
+        let timeouts = Timeouts {
+            handshake: Some(Duration::from_secs(5)),
+            ..Default::default()
+        };
+        let closed = relay(inbound, outbound, &instance(), &timeouts, false, Handshake::Forward).await;
+        assert_eq!(closed.reason, CloseReason::HandshakeTimeout);
+        assert!(start.elapsed() >= Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn check_idle_timeout() {
+        let (mut client, inbound) = pair().await;
+        let (outbound, upstream_side) = pair().await;
+        pause();
+        let start = Instant::now();
+        tokio::spawn(upstream(upstream_side));
+        // traffic at 6s moves the idle deadline from 10s to 16s
+        let client = tokio::spawn(async move {
+            client_handshake(&mut client).await;
+            tokio::time::sleep(Duration::from_secs(6)).await;
+            client.write_all(b"x").await.unwrap();
+            client
+        });
+        let timeouts = Timeouts {
+            idle: Some(Duration::from_secs(10)),
+            ..Default::default()
+        };
+        let closed = relay(inbound, outbound, &instance(), &timeouts, false, Handshake::Forward).await;
+        assert_eq!(closed.reason, CloseReason::IdleTimeout);
+        assert_eq!(closed.bytes_up, 1);
+        assert!(start.elapsed() >= Duration::from_secs(16));
+        client.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn check_lifetime_exceeded() {
+        let (mut client, inbound) = pair().await;
+        let (outbound, upstream_side) = pair().await;
+        pause();
+        let start = Instant::now();
+        tokio::spawn(upstream(upstream_side));
+        // never idle for long, still closed at the end of its lifetime
+        tokio::spawn(async move {
+            client_handshake(&mut client).await;
+            loop {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                if client.write_all(b"x").await.is_err() {
+                    break;
+                }
+            }
+        });
+        let timeouts = Timeouts {
+            idle: Some(Duration::from_secs(10)),
+            lifetime: Some(Duration::from_secs(30)),
+            ..Default::default()
+        };
+        let closed = relay(inbound, outbound, &instance(), &timeouts, false, Handshake::Forward).await;
+        assert_eq!(closed.reason, CloseReason::LifetimeExceeded);
+        assert!(closed.bytes_up >= 5);
+        assert!(start.elapsed() >= Duration::from_secs(30));
+    }
+
+    #[tokio::test]
+    async fn check_auth_handshake_deadline() {
+        let upstream_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let pool = TorPool::builder()
+            .upstreams(vec![UpstreamConfig {
+                addr: upstream_listener.local_addr().unwrap().to_string(),
+                credentials: None,
+                check_interval_ms: 0,
+                check_timeout_ms: 0,
+            }])
+            .build();
+        pool.start().await.unwrap();
+        let config = ProxyConfig {
+            handshake_timeout_ms: 10_000,
+            users: vec![UserConfig {
+                username: "user".to_string(),
+                password_hash: PasswordHash::new("secret", 1).unwrap().to_string(),
+                groups: vec![],
+                max_connections: None,
+            }],
+            ..Default::default()
+        };
+        let proxy = Proxy::new(pool.clone(), &config).unwrap();
+        // verified ahead: no blocking hash while the clock is paused
+        let users = proxy.users.read().unwrap().clone();
+        let (mut client, inbound) = pair().await;
+        let client_addr = client.local_addr().unwrap();
+        assert!(users.authenticate("user", "secret", client_addr.ip()).await.is_ok());
+        pause();
+
+        // 6s to authenticate, 6s more for the request: within the limit each, not together
+        let client = tokio::spawn(async move {
+            client.write_all(&[5, 1, 2]).await.unwrap();
+            let mut reply = [0u8; 2];
+            client.read_exact(&mut reply).await.unwrap();
+            assert_eq!(reply, [5, 2]);
+            tokio::time::sleep(Duration::from_secs(6)).await;
+            client.write_all(&[1, 4]).await.unwrap();
+            client.write_all(b"user").await.unwrap();
+            client.write_all(&[6]).await.unwrap();
+            client.write_all(b"secret").await.unwrap();
+            client.read_exact(&mut reply).await.unwrap();
+            assert_eq!(reply, [1, 0]);
+            tokio::time::sleep(Duration::from_secs(6)).await;
+            let _ = client.write_all(&REQUEST).await;
+            let mut buf = [0u8; 16];
+            assert!(!matches!(client.read(&mut buf).await, Ok(n) if n > 0));
+        });
+        tokio::spawn(async move {
+            let (stream, _) = upstream_listener.accept().await.unwrap();
+            upstream(stream).await;
+        });
+        proxy.clone().handle(inbound, client_addr).await;
+        assert_eq!(proxy.stats().closed(CloseReason::HandshakeTimeout), 1);
+        client.await.unwrap();
+        pool.shutdown().await;
+    }
+}
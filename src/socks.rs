@@ -0,0 +1,375 @@
+use crate::config::CredentialsConfig;
+use crate::error::SocksError;
+use std::error::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub const VERSION: u8 = 5;
+pub const VERSION_4: u8 = 4;
+pub const METHOD_NO_AUTH: u8 = 0x00;
+pub const METHOD_USERNAME_PASSWORD: u8 = 0x02;
+pub const METHOD_NOT_ACCEPTABLE: u8 = 0xff;
+pub const USERNAME_PASSWORD_VERSION: u8 = 1;
+pub const ATYP_IPV4: u8 = 1;
+pub const ATYP_DOMAIN: u8 = 3;
+pub const ATYP_IPV6: u8 = 4;
+// socks4 user id and socks4a host name
+const MAX_SOCKS4_STRING: usize = 255;
+
+// Reads client greeting, returns offered methods.
+pub async fn read_greeting<R>(client: &mut R) -> Result<Vec<u8>, Box<dyn Error>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut header = [0u8; 2];
+    client.read_exact(&mut header).await?;
+    if header[0] != VERSION {
+        return Err(SocksError::Version { version: header[0] }.into());
+    }
+    let mut methods = vec![0u8; header[1] as usize];
+    client.read_exact(&mut methods).await?;
+    Ok(methods)
+}
+
+// Authenticates on upstream with username/password (RFC 1929).
+pub async fn upstream_auth<S>(upstream: &mut S, credentials: &CredentialsConfig) -> Result<(), Box<dyn Error>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let username = credentials.username.as_bytes();
+    let password = credentials.password.as_bytes();
+    if username.len() > 255 || password.len() > 255 {
+        return Err(SocksError::CredentialTooLong.into());
+    }
+
+    upstream
+        .write_all(&[VERSION, 1, METHOD_USERNAME_PASSWORD])
+        .await?;
+    let mut reply = [0u8; 2];
+    upstream.read_exact(&mut reply).await?;
+    if reply != [VERSION, METHOD_USERNAME_PASSWORD] {
+        return Err(SocksError::UpstreamMethod.into());
+    }
+
+    let mut request = vec![USERNAME_PASSWORD_VERSION, username.len() as u8];
+    request.extend_from_slice(username);
+    request.push(password.len() as u8);
+    request.extend_from_slice(password);
+    upstream.write_all(&request).await?;
+    upstream.read_exact(&mut reply).await?;
+    if reply[1] != 0 {
+        return Err(SocksError::UpstreamAuth {
+            username: credentials.username.clone(),
+        }
+        .into());
+    }
+    Ok(())
+}
+
+// Client side speaks no-auth socks5, upstream requires credentials:
+// answer client greeting ourselves, authenticate on upstream, the rest (request/reply) is relayed as is.
+pub async fn bridge_auth<C, S>(
+    client: &mut C,
+    upstream: &mut S,
+    credentials: &CredentialsConfig,
+) -> Result<(), Box<dyn Error>>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let methods = read_greeting(client).await?;
+    if !methods.contains(&METHOD_NO_AUTH) {
+        client.write_all(&[VERSION, METHOD_NOT_ACCEPTABLE]).await?;
+        return Err(SocksError::NoAcceptableMethod.into());
+    }
+    upstream_auth(upstream, credentials).await?;
+    client.write_all(&[VERSION, METHOD_NO_AUTH]).await?;
+    Ok(())
+}
+
+// socks5 request or reply: VER CMD/REP RSV ATYP ADDR PORT
+pub async fn read_message<R>(reader: &mut R) -> Result<Vec<u8>, Box<dyn Error>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut message = vec![0u8; 4];
+    reader.read_exact(&mut message).await?;
+    if message[0] != VERSION {
+        return Err(SocksError::Version { version: message[0] }.into());
+    }
+    let addr_len = match message[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => {
+            let len = reader.read_u8().await?;
+            message.push(len);
+            len as usize
+        }
+        atyp => return Err(SocksError::AddressType { atyp }.into()),
+    };
+    let start = message.len();
+    message.resize(start + addr_len + 2, 0);
+    reader.read_exact(&mut message[start..]).await?;
+    Ok(message)
+}
+
+async fn read_nul_terminated<R>(reader: &mut R, message: &mut Vec<u8>) -> Result<(), Box<dyn Error>>
+where
+    R: AsyncRead + Unpin,
+{
+    for _ in 0..=MAX_SOCKS4_STRING {
+        let byte = reader.read_u8().await?;
+        message.push(byte);
+        if byte == 0 {
+            return Ok(());
+        }
+    }
+    Err(SocksError::CredentialTooLong.into())
+}
+
+// socks4/4a request after the version byte: CMD PORT IP USERID 0 [HOST 0]
+async fn read_request4<R>(reader: &mut R) -> Result<Vec<u8>, Box<dyn Error>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut message = vec![VERSION_4, 0, 0, 0, 0, 0, 0, 0];
+    reader.read_exact(&mut message[1..]).await?;
+    read_nul_terminated(reader, &mut message).await?;
+    // 0.0.0.x: socks4a, host name follows
+    if message[4..7] == [0, 0, 0] && message[7] != 0 {
+        read_nul_terminated(reader, &mut message).await?;
+    }
+    Ok(message)
+}
+
+// Relays the socks handshake message by message up to the reply to the client request,
+// so callers know when it is over. Upstream credentials are applied as in bridge_auth.
+pub async fn forward_handshake<C, S>(
+    client: &mut C,
+    upstream: &mut S,
+    credentials: Option<&CredentialsConfig>,
+) -> Result<(), Box<dyn Error>>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if let Some(credentials) = credentials {
+        bridge_auth(client, upstream, credentials).await?;
+    } else {
+        let version = client.read_u8().await?;
+        if version == VERSION_4 {
+            let request = read_request4(client).await?;
+            upstream.write_all(&request).await?;
+            let mut reply = [0u8; 8];
+            upstream.read_exact(&mut reply).await?;
+            client.write_all(&reply).await?;
+            return Ok(());
+        }
+        if version != VERSION {
+            return Err(SocksError::Version { version }.into());
+        }
+        let mut methods = vec![0u8; client.read_u8().await? as usize];
+        client.read_exact(&mut methods).await?;
+        upstream.write_all(&[VERSION, methods.len() as u8]).await?;
+        upstream.write_all(&methods).await?;
+        let mut reply = [0u8; 2];
+        upstream.read_exact(&mut reply).await?;
+        client.write_all(&reply).await?;
+        match reply[1] {
+            METHOD_NO_AUTH => {}
+            METHOD_USERNAME_PASSWORD => {
+                // VER ULEN UNAME PLEN PASSWD
+                let mut auth = vec![0u8; 2];
+                client.read_exact(&mut auth).await?;
+                let start = auth.len();
+                auth.resize(start + auth[1] as usize + 1, 0);
+                client.read_exact(&mut auth[start..]).await?;
+                let start = auth.len();
+                auth.resize(start + *auth.last().unwrap_or(&0) as usize, 0);
+                client.read_exact(&mut auth[start..]).await?;
+                upstream.write_all(&auth).await?;
+                upstream.read_exact(&mut reply).await?;
+                client.write_all(&reply).await?;
+            }
+            _ => return Err(SocksError::NoAcceptableMethod.into()),
+        }
+    }
+    relay_request(client, upstream).await
+}
+
+// socks5 request from the client, reply from upstream
+async fn relay_request<C, S>(client: &mut C, upstream: &mut S) -> Result<(), Box<dyn Error>>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let request = read_message(client).await?;
+    upstream.write_all(&request).await?;
+    let reply = read_message(upstream).await?;
+    client.write_all(&reply).await?;
+    Ok(())
+}
+
+// Client side of username/password authentication (RFC 1929) up to the credentials;
+// the caller checks them and answers with auth_reply.
+pub async fn read_client_auth<C>(client: &mut C) -> Result<CredentialsConfig, Box<dyn Error>>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+{
+    let methods = read_greeting(client).await?;
+    if !methods.contains(&METHOD_USERNAME_PASSWORD) {
+        client.write_all(&[VERSION, METHOD_NOT_ACCEPTABLE]).await?;
+        return Err(SocksError::AuthRequired.into());
+    }
+    client.write_all(&[VERSION, METHOD_USERNAME_PASSWORD]).await?;
+    // VER ULEN UNAME PLEN PASSWD
+    let version = client.read_u8().await?;
+    if version != USERNAME_PASSWORD_VERSION {
+        return Err(SocksError::AuthVersion { version }.into());
+    }
+    let mut username = vec![0u8; client.read_u8().await? as usize];
+    client.read_exact(&mut username).await?;
+    let mut password = vec![0u8; client.read_u8().await? as usize];
+    client.read_exact(&mut password).await?;
+    Ok(CredentialsConfig {
+        username: String::from_utf8_lossy(&username).to_string(),
+        password: String::from_utf8_lossy(&password).to_string(),
+    })
+}
+
+pub async fn auth_reply<C>(client: &mut C, success: bool) -> Result<(), Box<dyn Error>>
+where
+    C: AsyncWrite + Unpin,
+{
+    client.write_all(&[USERNAME_PASSWORD_VERSION, if success { 0 } else { 1 }]).await?;
+    Ok(())
+}
+
+// Rest of the handshake once the client is authenticated by dyn_tor: greets upstream
+// (with its credentials if any), then relays the client request and the reply.
+pub async fn continue_handshake<C, S>(
+    client: &mut C,
+    upstream: &mut S,
+    credentials: Option<&CredentialsConfig>,
+) -> Result<(), Box<dyn Error>>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    match credentials {
+        Some(credentials) => upstream_auth(upstream, credentials).await?,
+        None => {
+            upstream.write_all(&[VERSION, 1, METHOD_NO_AUTH]).await?;
+            let mut reply = [0u8; 2];
+            upstream.read_exact(&mut reply).await?;
+            if reply != [VERSION, METHOD_NO_AUTH] {
+                return Err(SocksError::UpstreamNoAuth.into());
+            }
+        }
+    }
+    relay_request(client, upstream).await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::CredentialsConfig;
+    use crate::socks::{auth_reply, bridge_auth, continue_handshake, forward_handshake, read_client_auth};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn check_bridge_auth() {
+        let (mut client, mut client_side) = tokio::io::duplex(64);
+        let (mut upstream, mut upstream_side) = tokio::io::duplex(64);
+        let credentials = CredentialsConfig {
+            username: "user".to_string(),
+            password: "pw".to_string(),
+        };
+
+        let upstream_task = tokio::spawn(async move {
+            let mut greeting = [0u8; 3];
+            upstream_side.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [5, 1, 2]);
+            upstream_side.write_all(&[5, 2]).await.unwrap();
+            let mut auth = [0u8; 9];
+            upstream_side.read_exact(&mut auth).await.unwrap();
+            assert_eq!(&auth, b"\x01\x04user\x02pw");
+            upstream_side.write_all(&[1, 0]).await.unwrap();
+        });
+
+        client.write_all(&[5, 2, 0, 1]).await.unwrap();
+        bridge_auth(&mut client_side, &mut upstream, &credentials)
+            .await
+            .unwrap();
+        let mut reply = [0u8; 2];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [5, 0]);
+        upstream_task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn check_forward_handshake() {
+        let (mut client, mut client_side) = tokio::io::duplex(64);
+        let (mut upstream, mut upstream_side) = tokio::io::duplex(64);
+
+        let upstream_task = tokio::spawn(async move {
+            let mut greeting = [0u8; 3];
+            upstream_side.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [5, 1, 0]);
+            upstream_side.write_all(&[5, 0]).await.unwrap();
+            let mut request = [0u8; 18];
+            upstream_side.read_exact(&mut request).await.unwrap();
+            assert_eq!(&request, b"\x05\x01\x00\x03\x0bexample.com\x00\x50");
+            upstream_side.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 80]).await.unwrap();
+            upstream_side.write_all(b"data").await.unwrap();
+        });
+
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        client.write_all(b"\x05\x01\x00\x03\x0bexample.com\x00\x50").await.unwrap();
+        forward_handshake(&mut client_side, &mut upstream, None).await.unwrap();
+        let mut reply = [0u8; 12];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [5, 0, 5, 0, 0, 1, 127, 0, 0, 1, 0, 80]);
+        upstream_task.await.unwrap();
+        // data after the reply is left for the relay
+        let mut data = [0u8; 4];
+        upstream.read_exact(&mut data).await.unwrap();
+        assert_eq!(&data, b"data");
+    }
+
+    #[tokio::test]
+    async fn check_client_auth() {
+        let (mut client, mut client_side) = tokio::io::duplex(64);
+        let (mut upstream, mut upstream_side) = tokio::io::duplex(64);
+
+        let upstream_task = tokio::spawn(async move {
+            let mut greeting = [0u8; 3];
+            upstream_side.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [5, 1, 0]);
+            upstream_side.write_all(&[5, 0]).await.unwrap();
+            let mut request = [0u8; 10];
+            upstream_side.read_exact(&mut request).await.unwrap();
+            assert_eq!(request, [5, 1, 0, 1, 10, 0, 0, 1, 0, 80]);
+            upstream_side.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 80]).await.unwrap();
+        });
+
+        client.write_all(&[5, 2, 0, 2]).await.unwrap();
+        client.write_all(b"\x01\x05alice\x02pw").await.unwrap();
+        client.write_all(&[5, 1, 0, 1, 10, 0, 0, 1, 0, 80]).await.unwrap();
+        let credentials = read_client_auth(&mut client_side).await.unwrap();
+        assert_eq!(credentials.username, "alice");
+        assert_eq!(credentials.password, "pw");
+        auth_reply(&mut client_side, true).await.unwrap();
+        continue_handshake(&mut client_side, &mut upstream, None).await.unwrap();
+        let mut reply = [0u8; 14];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [5, 2, 1, 0, 5, 0, 0, 1, 127, 0, 0, 1, 0, 80]);
+        upstream_task.await.unwrap();
+
+        // no username/password offered
+        let (mut client, mut client_side) = tokio::io::duplex(64);
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        assert!(read_client_auth(&mut client_side).await.is_err());
+        let mut reply = [0u8; 2];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [5, 0xff]);
+    }
+}
@@ -0,0 +1,221 @@
+use std::future::Future;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use tokio::io::{Interest, Ready};
+use tokio::net::{TcpStream, UnixStream};
+
+// Pipe capacity asked for: fewer splice calls than the default 64K. Pipes are limited per
+// user (fs.pipe-user-pages-soft), past that the kernel gives smaller ones.
+const PIPE_SIZE: libc::c_int = 256 * 1024;
+const BUFFER_SIZE: usize = 16 * 1024;
+
+// Sockets relayed with splice(2); readiness comes from tokio, the syscalls are done here.
+pub trait SpliceStream: AsRawFd + Sync {
+    fn ready(&self, interest: Interest) -> impl Future<Output = io::Result<Ready>> + Send;
+    fn try_io<R>(&self, interest: Interest, f: impl FnOnce() -> io::Result<R>) -> io::Result<R>;
+}
+
+impl SpliceStream for TcpStream {
+    fn ready(&self, interest: Interest) -> impl Future<Output = io::Result<Ready>> + Send {
+        TcpStream::ready(self, interest)
+    }
+
+    fn try_io<R>(&self, interest: Interest, f: impl FnOnce() -> io::Result<R>) -> io::Result<R> {
+        TcpStream::try_io(self, interest, f)
+    }
+}
+
+impl SpliceStream for UnixStream {
+    fn ready(&self, interest: Interest) -> impl Future<Output = io::Result<Ready>> + Send {
+        UnixStream::ready(self, interest)
+    }
+
+    fn try_io<R>(&self, interest: Interest, f: impl FnOnce() -> io::Result<R>) -> io::Result<R> {
+        UnixStream::try_io(self, interest, f)
+    }
+}
+
+// Non-blocking pipe the bytes of one direction pass through.
+pub struct Pipe {
+    read: OwnedFd,
+    write: OwnedFd,
+    // bytes moved per splice call, fits in the pipe
+    capacity: usize,
+}
+
+impl Pipe {
+    pub fn new() -> io::Result<Self> {
+        let mut fds: [libc::c_int; 2] = [-1; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let (read, write) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+        // failing to grow is fine, the pipe just keeps its size
+        let mut capacity = unsafe { libc::fcntl(fds[0], libc::F_SETPIPE_SZ, PIPE_SIZE) };
+        if capacity < 0 {
+            capacity = unsafe { libc::fcntl(fds[0], libc::F_GETPIPE_SZ) };
+        }
+        if capacity <= 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            read,
+            write,
+            capacity: capacity as usize,
+        })
+    }
+}
+
+fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    let flags = libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK;
+    let n = unsafe { libc::splice(from, std::ptr::null_mut(), to, std::ptr::null_mut(), len, flags) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(n as usize)
+}
+
+fn read(fd: RawFd, buf: &mut [u8]) -> io::Result<usize> {
+    let n = unsafe { libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(n as usize)
+}
+
+fn write(fd: RawFd, buf: &[u8]) -> io::Result<usize> {
+    let n = unsafe { libc::write(fd, buf.as_ptr() as *const libc::c_void, buf.len()) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(n as usize)
+}
+
+fn shutdown_write<W: SpliceStream>(writer: &W) -> io::Result<()> {
+    if unsafe { libc::shutdown(writer.as_raw_fd(), libc::SHUT_WR) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// splice doesn't support this fd pair
+fn is_unsupported(e: &io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(libc::EINVAL) | Some(libc::ENOSYS))
+}
+
+// Readable/writable first, then the syscall; WouldBlock clears tokio's readiness and waits again.
+async fn io_ready<S, R>(stream: &S, interest: Interest, mut f: impl FnMut() -> io::Result<R>) -> io::Result<R>
+where
+    S: SpliceStream,
+{
+    loop {
+        stream.ready(interest).await?;
+        match stream.try_io(interest, &mut f) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            res => return res,
+        }
+    }
+}
+
+async fn write_all<W: SpliceStream>(writer: &W, mut buf: &[u8], written: &mut impl FnMut(usize)) -> io::Result<()> {
+    while !buf.is_empty() {
+        let n = io_ready(writer, Interest::WRITABLE, || write(writer.as_raw_fd(), buf)).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        written(n);
+        buf = &buf[n..];
+    }
+    Ok(())
+}
+
+// Buffered copy for sockets splice refuses.
+async fn copy_buffered<R, W>(reader: &R, writer: &W, mut written: impl FnMut(usize)) -> io::Result<()>
+where
+    R: SpliceStream,
+    W: SpliceStream,
+{
+    let mut buf = vec![0u8; BUFFER_SIZE];
+    loop {
+        let n = io_ready(reader, Interest::READABLE, || read(reader.as_raw_fd(), &mut buf)).await?;
+        if n == 0 {
+            return shutdown_write(writer);
+        }
+        write_all(writer, &buf[..n], &mut written).await?;
+    }
+}
+
+// Moves bytes from reader to writer through the pipe until EOF, then shuts down writing to writer.
+// `written` gets the size of every chunk delivered. Falls back to a buffered copy when splice
+// isn't supported for these sockets.
+pub async fn copy<R, W>(reader: &R, writer: &W, pipe: &Pipe, mut written: impl FnMut(usize)) -> io::Result<()>
+where
+    R: SpliceStream,
+    W: SpliceStream,
+{
+    let mut first = true;
+    loop {
+        // the pipe is empty here, so EAGAIN can only come from the socket
+        let res = io_ready(reader, Interest::READABLE, || {
+            splice(reader.as_raw_fd(), pipe.write.as_raw_fd(), pipe.capacity)
+        })
+        .await;
+        let mut pending = match res {
+            Ok(0) => return shutdown_write(writer),
+            Ok(n) => n,
+            Err(e) if first && is_unsupported(&e) => {
+                log::debug!("splice unsupported ({}), copying through a buffer", e);
+                return copy_buffered(reader, writer, written).await;
+            }
+            Err(e) => return Err(e),
+        };
+        first = false;
+        while pending > 0 {
+            let n = io_ready(writer, Interest::WRITABLE, || {
+                splice(pipe.read.as_raw_fd(), writer.as_raw_fd(), pending)
+            })
+            .await?;
+            if n == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            written(n);
+            pending -= n;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::splice::{copy, Pipe};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    async fn socket_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let connect = TcpStream::connect(listener.local_addr().unwrap());
+        let (connected, accepted) = tokio::join!(connect, listener.accept());
+        (connected.unwrap(), accepted.unwrap().0)
+    }
+
+    #[tokio::test]
+    async fn check_splice_copy() {
+        let (mut client, inbound) = socket_pair().await;
+        let (outbound, mut server) = socket_pair().await;
+        let data: Vec<u8> = (0..1_000_000u32).map(|x| x as u8).collect();
+        let sent = data.clone();
+        let writer = tokio::spawn(async move {
+            client.write_all(&sent).await.unwrap();
+            client.shutdown().await.unwrap();
+        });
+
+        let pipe = Pipe::new().unwrap();
+        let mut total = 0;
+        let mut received = vec![];
+        let copy = copy(&inbound, &outbound, &pipe, |n| total += n);
+        let (res, _) = tokio::join!(copy, server.read_to_end(&mut received));
+        res.unwrap();
+        writer.await.unwrap();
+        assert_eq!(total, data.len());
+        assert!(received == data);
+    }
+}
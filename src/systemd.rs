@@ -0,0 +1,297 @@
+use crate::pool::{InstanceStatus, PoolEvent, TorPool};
+use std::future::Future;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio_stream::StreamExt;
+
+pub const NOTIFY_SOCKET_ENV: &str = "NOTIFY_SOCKET";
+pub const WATCHDOG_USEC_ENV: &str = "WATCHDOG_USEC";
+pub const WATCHDOG_PID_ENV: &str = "WATCHDOG_PID";
+pub const LISTEN_FDS_ENV: &str = "LISTEN_FDS";
+pub const LISTEN_PID_ENV: &str = "LISTEN_PID";
+pub const LISTEN_FDNAMES_ENV: &str = "LISTEN_FDNAMES";
+// sd_listen_fds(3)
+const LISTEN_FDS_START: RawFd = 3;
+
+// sd_notify(3) client
+#[derive(Debug, Clone)]
+pub struct Notifier {
+    socket: String,
+}
+
+impl Notifier {
+    pub fn new(socket: String) -> Self {
+        Self { socket }
+    }
+
+    pub fn from_env() -> Option<Self> {
+        Self::from_value(std::env::var(NOTIFY_SOCKET_ENV).ok())
+    }
+
+    // NOTIFY_SOCKET value, unset or empty: not run by systemd
+    fn from_value(socket: Option<String>) -> Option<Self> {
+        socket.filter(|x| !x.is_empty()).map(Self::new)
+    }
+
+    pub fn notify(&self, state: &str) -> std::io::Result<()> {
+        let socket = UnixDatagram::unbound()?;
+        match self.socket.strip_prefix('@') {
+            #[cfg(target_os = "linux")]
+            Some(name) => {
+                use std::os::linux::net::SocketAddrExt;
+                let addr = std::os::unix::net::SocketAddr::from_abstract_name(name.as_bytes())?;
+                socket.send_to_addr(state.as_bytes(), &addr)?;
+            }
+            #[cfg(not(target_os = "linux"))]
+            Some(_) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    "abstract notify socket",
+                ))
+            }
+            None => {
+                socket.send_to(state.as_bytes(), PathBuf::from(&self.socket))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn notify_logged(&self, state: &str) {
+        if let Err(e) = self.notify(state) {
+            log::warn!("sd_notify '{}' failed: {}", state.replace('\n', " "), e);
+        }
+    }
+}
+
+// half of WATCHDOG_USEC, as sd_watchdog_enabled(3) suggests
+pub fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = std::env::var(WATCHDOG_PID_ENV) {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+    let usec = std::env::var(WATCHDOG_USEC_ENV).ok()?.parse::<u64>().ok()?;
+    if usec == 0 {
+        return None;
+    }
+    Some(Duration::from_micros(usec / 2))
+}
+
+// Pings only while `is_alive` passes, so systemd restarts a wedged dyn_tor.
+pub async fn watchdog<F>(notifier: Notifier, interval: Duration, is_alive: F)
+where
+    F: Fn() -> bool,
+{
+    let mut timer = tokio::time::interval(interval);
+    let mut was_alive = true;
+    loop {
+        timer.tick().await;
+        let alive = is_alive();
+        if alive {
+            notifier.notify_logged("WATCHDOG=1");
+        } else if was_alive {
+            log::warn!("liveness check failed, not pinging systemd watchdog");
+        }
+        was_alive = alive;
+    }
+}
+
+fn ready_counts(pool: &TorPool) -> (usize, usize) {
+    let instances = pool.instances();
+    let ready = instances
+        .iter()
+        .filter(|x| x.status == InstanceStatus::Ready)
+        .count();
+    (ready, instances.len())
+}
+
+// Sends STATUS on every instance change and READY=1 once min_ready instances are bootstrapped.
+// Subscribes to pool events right away, so it can be created before TorPool::start.
+pub fn readiness(pool: &TorPool, min_ready: usize, notifier: Notifier) -> impl Future<Output = ()> {
+    let mut events = pool.events();
+    let pool = pool.clone();
+    async move {
+        let mut ready_sent = false;
+        while let Some(event) = events.next().await {
+            if let PoolEvent::Shutdown = event {
+                notifier.notify_logged("STOPPING=1");
+                break;
+            }
+            let (ready, total) = ready_counts(&pool);
+            let mut state = format!("STATUS=ready {}/{}", ready, total);
+            if !ready_sent && total > 0 && ready >= min_ready.min(total) {
+                ready_sent = true;
+                state = "READY=1\n".to_string() + &state;
+            }
+            notifier.notify_logged(&state);
+        }
+    }
+}
+
+fn socket_option(fd: RawFd, level: libc::c_int, name: libc::c_int) -> Option<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let res = unsafe { libc::getsockopt(fd, level, name, &mut value as *mut _ as *mut libc::c_void, &mut len) };
+    (res == 0).then_some(value)
+}
+
+// listening tcp socket (ipv4 or ipv6)
+fn is_tcp_listener(fd: RawFd) -> bool {
+    let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    if unsafe { libc::getsockname(fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len) } != 0 {
+        return false;
+    }
+    let family = addr.ss_family as libc::c_int;
+    (family == libc::AF_INET || family == libc::AF_INET6)
+        && socket_option(fd, libc::SOL_SOCKET, libc::SO_TYPE) == Some(libc::SOCK_STREAM)
+        && socket_option(fd, libc::SOL_SOCKET, libc::SO_ACCEPTCONN) == Some(1)
+}
+
+// Sockets passed by systemd socket activation (LISTEN_FDS), empty if none.
+// Fds get close-on-exec so tor children don't inherit them; ones that aren't listening tcp
+// sockets are skipped.
+pub fn listen_fds() -> Vec<std::net::TcpListener> {
+    let fds = std::env::var(LISTEN_FDS_ENV).ok();
+    let pid = std::env::var(LISTEN_PID_ENV).ok();
+    listen_fds_with(fds.as_deref(), pid.as_deref(), LISTEN_FDS_START)
+}
+
+// LISTEN_FDS and LISTEN_PID values, fds numbered from `start`
+fn listen_fds_with(fds: Option<&str>, pid: Option<&str>, start: RawFd) -> Vec<std::net::TcpListener> {
+    if pid.and_then(|x| x.parse::<u32>().ok()) != Some(std::process::id()) {
+        return vec![];
+    }
+    let count = fds.and_then(|x| x.parse::<RawFd>().ok()).unwrap_or(0);
+    (start..start + count)
+        .filter_map(|fd| {
+            unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+            if !is_tcp_listener(fd) {
+                log::warn!("socket passed by systemd (fd {}) is not a listening tcp socket, skipped", fd);
+                return None;
+            }
+            Some(unsafe { std::net::TcpListener::from_raw_fd(fd) })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::UpstreamConfig;
+    use crate::pool::TorPool;
+    use crate::systemd::{listen_fds_with, readiness, watchdog, Notifier};
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixDatagram;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn stub(name: &str) -> (PathBuf, UnixDatagram) {
+        let path = std::env::temp_dir().join(format!("dyn_tor_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let stub = UnixDatagram::bind(&path).unwrap();
+        stub.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        (path, stub)
+    }
+
+    fn recv(stub: &UnixDatagram) -> String {
+        let mut buf = [0u8; 64];
+        let len = stub.recv(&mut buf).unwrap();
+        String::from_utf8_lossy(&buf[..len]).to_string()
+    }
+
+    // how many STATUS updates come first depends on how fast pool events are seen
+    fn recv_skipping_status(stub: &UnixDatagram) -> String {
+        let mut state = recv(stub);
+        while state.starts_with("STATUS=") {
+            state = recv(stub);
+        }
+        state
+    }
+
+    #[test]
+    fn check_notify() {
+        let path = std::env::temp_dir().join(format!("dyn_tor_notify_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let stub = UnixDatagram::bind(&path).unwrap();
+        assert!(Notifier::from_value(None).is_none());
+        assert!(Notifier::from_value(Some(String::new())).is_none());
+        let notifier = Notifier::from_value(Some(path.to_str().unwrap().to_string())).unwrap();
+        notifier.notify("READY=1\nSTATUS=ready 1/2").unwrap();
+        let mut buf = [0u8; 64];
+        let len = stub.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1\nSTATUS=ready 1/2");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    // multi thread: the stub blocks the test's thread
+    #[tokio::test(flavor = "multi_thread")]
+    async fn check_readiness() {
+        let (path, stub) = stub("readiness");
+        let notifier = Notifier::new(path.to_str().unwrap().to_string());
+        let upstream = |addr: &str| UpstreamConfig {
+            addr: addr.to_string(),
+            credentials: None,
+            check_interval_ms: 0,
+            check_timeout_ms: 0,
+        };
+        let pool = TorPool::builder()
+            .upstreams(vec![upstream("127.0.0.1:1"), upstream("127.0.0.1:2")])
+            .build();
+        let task = tokio::spawn(readiness(&pool, 2, notifier));
+        pool.start().await.unwrap();
+        assert_eq!(recv_skipping_status(&stub), "READY=1\nSTATUS=ready 2/2");
+        pool.shutdown().await;
+        assert_eq!(recv_skipping_status(&stub), "STOPPING=1");
+        task.await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn check_watchdog() {
+        let (path, stub) = stub("watchdog");
+        let alive = Arc::new(AtomicBool::new(true));
+        let is_alive = alive.clone();
+        let notifier = Notifier::new(path.to_str().unwrap().to_string());
+        let task = tokio::spawn(watchdog(notifier, Duration::from_millis(20), move || is_alive.load(Ordering::SeqCst)));
+        assert_eq!(recv(&stub), "WATCHDOG=1");
+        alive.store(false, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // drain pings sent before the switch
+        stub.set_nonblocking(true).unwrap();
+        while stub.recv(&mut [0u8; 64]).is_ok() {}
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(stub.recv(&mut [0u8; 64]).is_err());
+        stub.set_nonblocking(false).unwrap();
+        alive.store(true, Ordering::SeqCst);
+        assert_eq!(recv(&stub), "WATCHDOG=1");
+        task.abort();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn check_listen_fds() {
+        // passed fds are consecutive; placed high so they don't meet fds of the test harness
+        const START: i32 = 900;
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let not_listening = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        for (i, fd) in [listener.as_raw_fd(), udp.as_raw_fd(), not_listening.as_raw_fd()].iter().enumerate() {
+            assert_eq!(unsafe { libc::dup2(*fd, START + i as i32) }, START + i as i32);
+        }
+
+        // for another process
+        assert!(listen_fds_with(Some("3"), Some("1"), START).is_empty());
+        assert!(listen_fds_with(Some("3"), None, START).is_empty());
+        let pid = std::process::id().to_string();
+        let passed = listen_fds_with(Some("3"), Some(&pid), START);
+        assert_eq!(passed.len(), 1);
+        assert_eq!(passed[0].local_addr().unwrap(), listener.local_addr().unwrap());
+        for fd in START + 1..START + 3 {
+            unsafe { libc::close(fd) };
+        }
+    }
+}
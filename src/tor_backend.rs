@@ -0,0 +1,1080 @@
+use crate::backend::Backend;
+use crate::config::{PortModeConfig, TorConfig};
+use crate::data_dirs;
+use crate::limits;
+use crate::torrc;
+use crate::error::{BackendError, ConfigFileError, TorSpawnError, TorrcError};
+use crate::pool::{InstanceInfo, InstanceStatus, PoolEvent, PoolHandle, SocksAddr};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::io::ErrorKind;
+use std::net::TcpListener;
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::process::{Child, Command};
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+
+pub const KIND: &str = "tor";
+
+const BOOTSTRAPPED_LINE: &str = "Bootstrapped 100%";
+
+pub const UNIX_SOCKET_NAME: &str = "socks.sock";
+
+// rendered per instance torrc in its data dir
+pub const RENDERED_TORRC: &str = "torrc";
+
+// --version and --verify-config runs
+const TOR_CHECK_TIMEOUT: Duration = Duration::from_secs(30);
+
+// last output lines kept per instance for early exit reports
+const OUTPUT_TAIL_LINES: usize = 10;
+// output still in the pipes after an early exit
+const OUTPUT_DRAIN_TIMEOUT: Duration = Duration::from_millis(500);
+
+const INHERITED_ENV_BLOCKLIST: [&str; 6] = [
+    "NOTIFY_SOCKET",
+    "WATCHDOG_USEC",
+    "WATCHDOG_PID",
+    "LISTEN_FDS",
+    "LISTEN_PID",
+    "LISTEN_FDNAMES",
+];
+
+// sockaddr_un::sun_path without trailing zero
+#[cfg(target_os = "linux")]
+const MAX_UNIX_SOCKET_PATH: usize = 107;
+#[cfg(not(target_os = "linux"))]
+const MAX_UNIX_SOCKET_PATH: usize = 103;
+
+// tor instances spawned and owned by dyn_tor
+#[derive(Clone)]
+pub struct TorBackend {
+    config: TorConfig,
+}
+
+impl TorBackend {
+    pub fn new(mut config: TorConfig) -> Self {
+        // config not passed thru init::init: use paths as is
+        if config.full_path.is_empty() {
+            config.full_path = config.path.clone();
+        }
+        if config.torrc_full_path.is_empty() {
+            config.torrc_full_path = config.torrc.clone();
+        }
+        if config.data_dirs.full_path.is_empty() {
+            config.data_dirs.full_path = config.data_dirs.path.clone();
+        }
+        if config.working_dir_full_path.is_empty() {
+            config.working_dir_full_path = config.working_dir.clone().unwrap_or_default();
+        }
+        Self { config }
+    }
+
+    pub fn config(&self) -> &TorConfig {
+        &self.config
+    }
+
+    fn spawn_error(&self, e: std::io::Error) -> TorSpawnError {
+        // wrapper is what gets executed
+        let path = self.config.wrapper.first().unwrap_or(&self.config.full_path).clone();
+        match e.kind() {
+            ErrorKind::NotFound => TorSpawnError::NotFound { path },
+            _ => TorSpawnError::Other {
+                path,
+                error: e.to_string(),
+            },
+        }
+    }
+
+    // Socks ports (none for Unix), then control ports if control_start_port is set.
+    // Fixed: whole ranges must be free; Auto: ports picked by os.
+    // Probe listeners are held until all ports are chosen so they don't repeat.
+    pub fn allocate_ports(&self) -> Result<Vec<u16>, TorSpawnError> {
+        let config = &self.config;
+        let mut ranges = vec![];
+        if config.port_mode != PortModeConfig::Unix {
+            ranges.push(config.start_port..config.start_port + config.port_count);
+        }
+        if let Some(control_start_port) = config.control_start_port {
+            ranges.push(control_start_port..control_start_port + config.port_count);
+        }
+        match config.port_mode {
+            PortModeConfig::Fixed | PortModeConfig::Unix => {
+                let busy: Vec<String> = ranges
+                    .iter()
+                    .flat_map(|x| x.clone())
+                    .filter(|port| TcpListener::bind(("127.0.0.1", *port)).is_err())
+                    .map(|port| port.to_string())
+                    .collect();
+                if busy.is_empty() {
+                    Ok(ranges.into_iter().flatten().collect())
+                } else {
+                    Err(TorSpawnError::PortsBusy {
+                        ports: busy.join(", "),
+                    })
+                }
+            }
+            PortModeConfig::Auto => {
+                let listeners = (0..ranges.iter().map(|x| x.len()).sum())
+                    .map(|_| TcpListener::bind(("127.0.0.1", 0)))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| TorSpawnError::NoFreePort {
+                        error: e.to_string(),
+                    })?;
+                listeners
+                    .iter()
+                    .map(|x| x.local_addr().map(|x| x.port()))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| TorSpawnError::NoFreePort {
+                        error: e.to_string(),
+                    })
+            }
+        }
+    }
+
+    fn data_dir(&self, index: usize, port: u16) -> String {
+        match self.config.port_mode {
+            PortModeConfig::Fixed => self.config.data_dirs.full_path.clone() + &port.to_string(),
+            // ports differ between runs
+            PortModeConfig::Auto => self.config.data_dirs.full_path.clone() + "auto_" + &index.to_string(),
+            PortModeConfig::Unix => self.config.data_dirs.full_path.clone() + "unix_" + &index.to_string(),
+        }
+    }
+
+    pub fn plan_instances(&self) -> Result<Vec<InstancePlan>, TorSpawnError> {
+        let count = self.config.port_count as usize;
+        let mut ports = self.allocate_ports()?;
+        let control_ports = match self.config.control_start_port {
+            Some(_) => ports.split_off(ports.len() - count).into_iter().map(Some).collect(),
+            None => vec![None; count],
+        };
+        let groups = &self.config.groups;
+        (0..count)
+            .zip(control_ports)
+            .map(|(position, control_port)| {
+                let (data_dir, socks_addr) = match self.config.port_mode {
+                    PortModeConfig::Unix => {
+                        let data_dir = self.data_dir(position, 0);
+                        let path = data_dir.clone() + "/" + UNIX_SOCKET_NAME;
+                        if path.len() > MAX_UNIX_SOCKET_PATH {
+                            return Err(TorSpawnError::SocketPathTooLong {
+                                path,
+                                max: MAX_UNIX_SOCKET_PATH,
+                            });
+                        }
+                        (data_dir, SocksAddr::Unix(path))
+                    }
+                    _ => {
+                        let port = ports[position];
+                        (
+                            self.data_dir(position, port),
+                            SocksAddr::Tcp("127.0.0.1:".to_string() + &port.to_string()),
+                        )
+                    }
+                };
+                Ok(InstancePlan {
+                    position,
+                    data_dir,
+                    socks_addr,
+                    control_port,
+                    group: (!groups.is_empty()).then(|| groups[position % groups.len()].clone()),
+                })
+            })
+            .collect()
+    }
+
+    fn torrc_source(&self) -> String {
+        match self.config.torrc_template {
+            Some(_) => "tor.torrc_template".to_string(),
+            None => self.config.torrc_full_path.clone(),
+        }
+    }
+
+    fn torrc_template(&self) -> Result<String, TorSpawnError> {
+        match &self.config.torrc_template {
+            Some(template) => Ok(template.clone()),
+            None => {
+                let text = std::fs::read_to_string(&self.config.torrc_full_path).map_err(|e| {
+                    TorSpawnError::Torrc(TorrcError::Read {
+                        path: self.torrc_source(),
+                        error: e.to_string(),
+                    })
+                })?;
+                let dir = Path::new(&self.config.torrc_full_path).parent().unwrap_or_else(|| Path::new(""));
+                Ok(torrc::absolutize(&text, dir))
+            }
+        }
+    }
+
+    // Renders torrc of the instance into its data dir, returns the rendered file path.
+    fn write_torrc(&self, plan: &InstancePlan) -> Result<String, TorSpawnError> {
+        let torrc = render_template(&self.torrc_template()?, plan).map_err(|error| TorSpawnError::Template {
+            name: self.torrc_source(),
+            error,
+        })?;
+        let path = plan.data_dir.clone() + "/" + RENDERED_TORRC;
+        std::fs::write(&path, torrc).map_err(|e| TorSpawnError::DataDir {
+            path: path.clone(),
+            error: e.to_string(),
+        })?;
+        Ok(path)
+    }
+
+    fn socks_port_arg(socks_addr: &SocksAddr) -> String {
+        match socks_addr {
+            SocksAddr::Tcp(addr) => addr.clone(),
+            SocksAddr::Unix(path) if path.contains(' ') => format!("unix:\"{}\"", path),
+            SocksAddr::Unix(path) => format!("unix:{}", path),
+        }
+    }
+
+    // tor refuses group/world accessible unix socket dirs, and only owner should reach the socket
+    #[cfg(unix)]
+    fn prepare_data_dir(data_dir: &str, _unix_socket: bool) -> Result<(), TorSpawnError> {
+        use std::os::unix::fs::PermissionsExt;
+        let error = |e: std::io::Error| TorSpawnError::DataDir {
+            path: data_dir.to_string(),
+            error: e.to_string(),
+        };
+        std::fs::create_dir_all(data_dir).map_err(error)?;
+        std::fs::set_permissions(data_dir, std::fs::Permissions::from_mode(0o700)).map_err(error)
+    }
+
+    #[cfg(not(unix))]
+    fn prepare_data_dir(data_dir: &str, unix_socket: bool) -> Result<(), TorSpawnError> {
+        if unix_socket {
+            return Err(TorSpawnError::UnixSocketsUnsupported);
+        }
+        std::fs::create_dir_all(data_dir).map_err(|e| TorSpawnError::DataDir {
+            path: data_dir.to_string(),
+            error: e.to_string(),
+        })
+    }
+
+    // Own process group (killed as a whole on shutdown) and, on linux, death signal
+    // when dyn_tor dies without cleanup. pdeathsig follows the thread that forks, so
+    // children have to be spawned with spawn_child.
+    #[cfg(unix)]
+    fn set_child_process_options(command: &mut Command) {
+        #[cfg(target_os = "linux")]
+        let parent = std::process::id();
+        unsafe {
+            command.pre_exec(move || {
+                if libc::setpgid(0, 0) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                #[cfg(target_os = "linux")]
+                {
+                    if libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM) != 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                    // parent died before prctl
+                    if libc::getppid() as u32 != parent {
+                        return Err(std::io::Error::other("dyn_tor exited"));
+                    }
+                }
+                Ok(())
+            });
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn set_child_process_options(_command: &mut Command) {}
+
+    // tor (or wrapper followed by tor) with configured environment and working dir
+    fn command(&self) -> Command {
+        let config = &self.config;
+        let mut command = match config.wrapper.split_first() {
+            Some((program, args)) => {
+                let mut command = Command::new(program);
+                command.args(args).arg(&config.full_path);
+                command
+            }
+            None => Command::new(&config.full_path),
+        };
+        if config.clear_env {
+            command.env_clear();
+        } else {
+            // tor built with systemd support would talk to our notify socket
+            for name in INHERITED_ENV_BLOCKLIST {
+                command.env_remove(name);
+            }
+        }
+        command.envs(&config.env);
+        if !config.working_dir_full_path.is_empty() {
+            command.current_dir(&config.working_dir_full_path);
+        }
+        command
+    }
+
+    fn instance_args(&self, plan: &InstancePlan, torrc: &str) -> Result<Vec<String>, TorSpawnError> {
+        let mut res = vec![
+            "-f".to_string(),
+            torrc.to_string(),
+            "--SocksPort".to_string(),
+            Self::socks_port_arg(&plan.socks_addr),
+            "--DataDirectory".to_string(),
+            plan.data_dir.clone(),
+        ];
+        for arg in &self.config.extra_args {
+            res.push(render_template(arg, plan).map_err(|error| TorSpawnError::Template {
+                name: "tor.extra_args".to_string(),
+                error,
+            })?);
+        }
+        Ok(res)
+    }
+
+    // Planned instances with ports, data dir, exact command line and rendered torrc.
+    // Nothing is written; Auto mode ports are picked again on start.
+    pub fn describe_plan(&self) -> Result<String, TorSpawnError> {
+        let template = self.torrc_template()?;
+        let mut res = String::new();
+        for plan in self.plan_instances()? {
+            let torrc_path = plan.data_dir.clone() + "/" + RENDERED_TORRC;
+            let torrc = render_template(&template, &plan).map_err(|error| TorSpawnError::Template {
+                name: self.torrc_source(),
+                error,
+            })?;
+            let mut command = self.command();
+            command.args(self.instance_args(&plan, &torrc_path)?);
+            let command = command.as_std();
+            let command_line = std::iter::once(command.get_program())
+                .chain(command.get_args())
+                .map(|x| quote_arg(&x.to_string_lossy()))
+                .collect::<Vec<_>>()
+                .join(" ");
+
+            let _ = writeln!(res, "instance {}:", plan.position);
+            let _ = writeln!(res, "  socks: {}", plan.socks_addr);
+            if let Some(port) = plan.control_port {
+                let _ = writeln!(res, "  control port: {}", port);
+            }
+            if let Some(group) = &plan.group {
+                let _ = writeln!(res, "  group: {}", group);
+            }
+            let _ = writeln!(res, "  data dir: {}", plan.data_dir);
+            let _ = writeln!(res, "  command: {}", command_line);
+            if self.config.clear_env || !self.config.env.is_empty() {
+                let env = command
+                    .get_envs()
+                    .filter_map(|(name, value)| {
+                        Some(format!("{}={}", name.to_string_lossy(), quote_arg(&value?.to_string_lossy())))
+                    })
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                let clear = if self.config.clear_env { " (only)" } else { "" };
+                let _ = writeln!(res, "  env{}: {}", clear, env);
+            }
+            if let Some(dir) = command.get_current_dir() {
+                let _ = writeln!(res, "  working dir: {}", dir.to_string_lossy());
+            }
+            let _ = writeln!(res, "  torrc {}:", torrc_path);
+            for line in torrc.lines() {
+                let _ = writeln!(res, "    {}", line);
+            }
+        }
+        Ok(res)
+    }
+
+    async fn run_tor(&self, args: Vec<String>) -> Result<(bool, String), TorSpawnError> {
+        let output = self.command().args(args).kill_on_drop(true).output();
+        let output = tokio::time::timeout(TOR_CHECK_TIMEOUT, output)
+            .await
+            .map_err(|_| TorSpawnError::Other {
+                path: self.config.full_path.clone(),
+                error: format!("no answer in {:?}", TOR_CHECK_TIMEOUT),
+            })?
+            .map_err(|e| self.spawn_error(e))?;
+        let mut text = String::from_utf8_lossy(&output.stdout).to_string();
+        text += &String::from_utf8_lossy(&output.stderr);
+        Ok((output.status.success(), text))
+    }
+
+    // tor --version and tor --verify-config with the same arguments the first instance gets
+    pub async fn verify(&self, plan: &InstancePlan) -> Result<(), TorSpawnError> {
+        let (_, output) = self.run_tor(vec!["--version".to_string()]).await?;
+        let version = parse_tor_version(&output).ok_or_else(|| TorSpawnError::VersionUnknown {
+            path: self.config.full_path.clone(),
+            output: output.trim().to_string(),
+        })?;
+        log::info!("tor version {}", version_string(&version));
+        if let Some(min_version) = &self.config.min_version {
+            let min = parse_min_version(min_version)?;
+            if version < min {
+                return Err(TorSpawnError::Version {
+                    version: version_string(&version),
+                    min_version: min_version.clone(),
+                });
+            }
+        }
+
+        Self::prepare_data_dir(&plan.data_dir, matches!(plan.socks_addr, SocksAddr::Unix(_)))?;
+        let torrc = self.write_torrc(plan)?;
+        let mut args = vec!["--verify-config".to_string()];
+        args.extend(self.instance_args(plan, &torrc)?);
+        let (ok, output) = self.run_tor(args).await?;
+        if !ok {
+            return Err(TorSpawnError::InvalidConfig {
+                torrc,
+                output: output.trim().to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    fn spawn_instance(&self, pool: &PoolHandle, plan: InstancePlan) -> Result<(usize, Child), TorSpawnError> {
+        let config = &self.config;
+        data_dirs::prepare_instance(&config.data_dirs, &plan.data_dir)?;
+        Self::prepare_data_dir(&plan.data_dir, matches!(plan.socks_addr, SocksAddr::Unix(_)))?;
+        let torrc = self.write_torrc(&plan)?;
+        let child_limits = limits::prepare(&config.limits, plan.position)?;
+        let mut command = self.command();
+        Self::set_child_process_options(&mut command);
+        #[cfg(unix)]
+        if !child_limits.is_empty() {
+            unsafe {
+                command.pre_exec(move || limits::apply_in_child(&child_limits));
+            }
+        }
+        command
+            .args(self.instance_args(&plan, &torrc)?)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        let child = spawn_child(command).map_err(|e| self.spawn_error(e))?;
+        if let Some(pid) = child.id() {
+            data_dirs::write_child_pid(&plan.data_dir, pid).map_err(|e| TorSpawnError::DataDir {
+                path: plan.data_dir.clone(),
+                error: e.to_string(),
+            })?;
+        }
+        let index = pool.add_instance(InstanceInfo {
+            index: 0,
+            backend: KIND,
+            socks_addr: plan.socks_addr,
+            credentials: config.credentials.clone(),
+            data_dir: Some(plan.data_dir),
+            group: plan.group,
+            pid: child.id(),
+            status: InstanceStatus::Starting,
+        });
+        Ok((index, child))
+    }
+}
+
+type SpawnJob = Box<dyn FnOnce() + Send>;
+
+// Forks from one thread that lives as long as dyn_tor: a tokio worker, blocking pool thread
+// (gone after 10s idle) or another runtime's thread exiting would fire the children's
+// pdeathsig and stop tor at random. Waits for the fork, which is short.
+fn spawn_child(mut command: Command) -> std::io::Result<Child> {
+    static SPAWNER: OnceLock<Option<mpsc::Sender<SpawnJob>>> = OnceLock::new();
+    let spawner = SPAWNER.get_or_init(|| {
+        let (sender, receiver) = mpsc::channel::<SpawnJob>();
+        let thread = std::thread::Builder::new().name("dyn_tor-spawn".to_string()).spawn(move || {
+            for job in receiver {
+                job();
+            }
+        });
+        thread.ok().map(|_| sender)
+    });
+    let spawner = spawner
+        .as_ref()
+        .ok_or_else(|| std::io::Error::other("can't start the process spawning thread"))?;
+    // the child is registered with the caller's runtime for reaping
+    let runtime = tokio::runtime::Handle::current();
+    let (sender, receiver) = mpsc::sync_channel(1);
+    let job: SpawnJob = Box::new(move || {
+        let _runtime = runtime.enter();
+        let _ = sender.send(command.spawn());
+    });
+    spawner
+        .send(job)
+        .map_err(|_| std::io::Error::other("process spawning thread exited"))?;
+    receiver
+        .recv()
+        .map_err(|_| std::io::Error::other("process spawning thread exited"))?
+}
+
+// what one tor instance is spawned with
+#[derive(Debug, Clone)]
+pub struct InstancePlan {
+    // position in tor.port_count range, {index} in torrc
+    pub position: usize,
+    pub data_dir: String,
+    pub socks_addr: SocksAddr,
+    pub control_port: Option<u16>,
+    pub group: Option<String>,
+}
+
+// Substitutes {index}, {socks_port}, {control_port}, {data_dir} and {group}.
+// Other braces are left as is: torrc uses them for country codes ("ExitNodes {us}").
+pub fn render_template(template: &str, plan: &InstancePlan) -> Result<String, String> {
+    let socks_port = match &plan.socks_addr {
+        SocksAddr::Tcp(addr) => addr.rsplit(':').next().unwrap_or_default().to_string(),
+        SocksAddr::Unix(_) => TorBackend::socks_port_arg(&plan.socks_addr),
+    };
+    let mut res = template
+        .replace("{index}", &plan.position.to_string())
+        .replace("{socks_port}", &socks_port)
+        .replace("{data_dir}", &plan.data_dir);
+    for (name, value, setting) in [
+        ("{control_port}", plan.control_port.map(|x| x.to_string()), "tor.control_start_port"),
+        ("{group}", plan.group.clone(), "tor.groups"),
+    ] {
+        if res.contains(name) {
+            match value {
+                Some(value) => res = res.replace(name, &value),
+                None => return Err(format!("{} is used but {} is not set", name, setting)),
+            }
+        }
+    }
+    Ok(res)
+}
+
+// shell style quoting for printed command lines
+fn quote_arg(arg: &str) -> String {
+    if !arg.is_empty() && arg.chars().all(|x| x.is_ascii_alphanumeric() || "-_./:=@%+,".contains(x)) {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+// tor.min_version: up to 4 numbers separated by dots, nothing else
+pub fn parse_min_version(s: &str) -> Result<Vec<u32>, ConfigFileError> {
+    let error = |error: &str| ConfigFileError::InvalidParameter {
+        name: "tor.min_version".to_string(),
+        value: s.to_string(),
+        error: error.to_string(),
+    };
+    let res = s
+        .split('.')
+        .map(|x| match x.bytes().all(|x| x.is_ascii_digit()) {
+            true => x.parse::<u32>().ok(),
+            false => None,
+        })
+        .collect::<Option<Vec<u32>>>()
+        .ok_or_else(|| error("expected a version like 0.4.7.13"))?;
+    if res.len() > 4 {
+        return Err(error("more than 4 numbers"));
+    }
+    Ok(res)
+}
+
+// "1.2.3.4" -> [1, 2, 3, 4]; trailing non-digits of a part ("0-alpha") are ignored
+fn parse_version(s: &str) -> Option<Vec<u32>> {
+    let res: Vec<u32> = s
+        .split('.')
+        .map_while(|x| {
+            let digits: String = x.chars().take_while(|x| x.is_ascii_digit()).collect();
+            digits.parse().ok()
+        })
+        .collect();
+    if res.is_empty() {
+        None
+    } else {
+        Some(res)
+    }
+}
+
+// "Tor version 0.4.7.13." -> [0, 4, 7, 13]
+fn parse_tor_version(output: &str) -> Option<Vec<u32>> {
+    output
+        .lines()
+        .find_map(|x| x.split("Tor version ").nth(1))
+        .and_then(parse_version)
+}
+
+fn version_string(version: &[u32]) -> String {
+    version.iter().map(|x| x.to_string()).collect::<Vec<_>>().join(".")
+}
+
+// instance exited within early_exit_ms after spawn
+struct EarlyExit {
+    index: usize,
+    code: Option<i32>,
+    output: Vec<String>,
+}
+
+// startup progress shared by instance watchers
+struct Startup {
+    ready: AtomicUsize,
+    total: usize,
+    early_exit: Duration,
+    early_exits: Mutex<Vec<EarlyExit>>,
+    // on every ready instance and early exit
+    changed: Notify,
+}
+
+impl Startup {
+    fn early_exit_error(&self, indexes: &[usize]) -> TorSpawnError {
+        let early_exits = self.early_exits.lock().unwrap();
+        let failed: Vec<_> = early_exits.iter().filter(|x| indexes.contains(&x.index)).collect();
+        let details = failed
+            .iter()
+            .map(|x| {
+                let code = x.code.map(|x| x.to_string()).unwrap_or_else(|| "none".to_string());
+                let mut res = format!("{}: exit code {}", x.index, code);
+                for line in &x.output {
+                    res += "\n    ";
+                    res += line;
+                }
+                res
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        TorSpawnError::EarlyExits {
+            failed: failed.len(),
+            total: indexes.len(),
+            details,
+        }
+    }
+}
+
+// last lines of an instance output
+type OutputTail = Arc<Mutex<VecDeque<String>>>;
+
+// startup_concurrency permit, held until the instance bootstraps or exits
+type StartupSlot = Arc<Mutex<Option<OwnedSemaphorePermit>>>;
+
+impl TorBackend {
+    fn launch(
+        &self,
+        pool: &PoolHandle,
+        plan: InstancePlan,
+        startup: Arc<Startup>,
+        slot: Option<OwnedSemaphorePermit>,
+    ) -> Result<usize, TorSpawnError> {
+        log::debug!("tor instance: socks '{}', data dir '{}'", plan.socks_addr, plan.data_dir);
+        let data_dir = plan.data_dir.clone();
+        let (index, child) = self.spawn_instance(pool, plan)?;
+        pool.send_event(PoolEvent::Spawned {
+            index,
+            pid: child.id(),
+        });
+        let slot = Arc::new(Mutex::new(slot));
+        pool.spawn_task(watch_instance(pool.clone(), index, child, data_dir, startup, slot));
+        Ok(index)
+    }
+
+    // Waits until each of the first batch is ready, exited early or older than early_exit_ms.
+    // Too many early exits mean a broken setup (bad option, busy port, ...): fail the start.
+    async fn check_early_exits(
+        &self,
+        pool: &PoolHandle,
+        startup: &Startup,
+        indexes: &[usize],
+    ) -> Result<(), TorSpawnError> {
+        let deadline = tokio::time::Instant::now() + startup.early_exit;
+        loop {
+            let failed = startup
+                .early_exits
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|x| indexes.contains(&x.index))
+                .count();
+            if failed as f64 > indexes.len() as f64 * self.config.max_early_exit_ratio {
+                return Err(startup.early_exit_error(indexes));
+            }
+            let ready = indexes
+                .iter()
+                .filter(|x| pool.status(**x) == Some(InstanceStatus::Ready))
+                .count();
+            if ready + failed == indexes.len() {
+                break;
+            }
+            tokio::select! {
+                _ = startup.changed.notified() => {}
+                _ = tokio::time::sleep_until(deadline) => break,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Backend for TorBackend {
+    fn kind(&self) -> &'static str {
+        KIND
+    }
+
+    // At most startup_concurrency instances bootstrap at once: the first batch is spawned here
+    // (so spawn errors are returned), the rest by a background task as slots free up.
+    fn start(&self, pool: PoolHandle) -> BoxFuture<'_, Result<(), BackendError>> {
+        async move {
+            let issues = torrc::check_config(&self.config).map_err(TorSpawnError::from)?;
+            for issue in &issues {
+                log::warn!("torrc: {}", issue);
+            }
+            torrc::enforce(&self.config, &issues).map_err(TorSpawnError::from)?;
+            let instances = self.plan_instances()?;
+            if let (true, Some(plan)) = (self.config.verify, instances.first()) {
+                self.verify(plan).await?;
+            }
+            if self.config.data_dirs.seed {
+                let root = &self.config.data_dirs.full_path;
+                data_dirs::refresh_seed(root).map_err(|e| TorSpawnError::DataDir {
+                    path: root.clone(),
+                    error: e.to_string(),
+                })?;
+            }
+            let startup = Arc::new(Startup {
+                ready: AtomicUsize::new(0),
+                total: instances.len(),
+                early_exit: Duration::from_millis(self.config.early_exit_ms),
+                early_exits: Mutex::new(vec![]),
+                changed: Notify::new(),
+            });
+            let delay = Duration::from_millis(self.config.startup_delay_ms);
+            let slots = self
+                .config
+                .startup_concurrency
+                .filter(|x| *x > 0)
+                .map(|x| Arc::new(Semaphore::new(x)));
+            let first_batch = slots
+                .as_ref()
+                .map(|x| x.available_permits())
+                .unwrap_or(instances.len());
+
+            let mut instances = instances.into_iter();
+            let mut first_indexes = vec![];
+            for plan in instances.by_ref().take(first_batch) {
+                if plan.position > 0 && !delay.is_zero() {
+                    tokio::time::sleep(delay).await;
+                }
+                let slot = slots.as_ref().and_then(|x| x.clone().try_acquire_owned().ok());
+                first_indexes.push(self.launch(&pool, plan, startup.clone(), slot)?);
+            }
+            if !startup.early_exit.is_zero() {
+                self.check_early_exits(&pool, &startup, &first_indexes).await?;
+            }
+
+            let rest: Vec<_> = instances.collect();
+            if let (Some(slots), false) = (slots, rest.is_empty()) {
+                let backend = self.clone();
+                let task_pool = pool.clone();
+                let launch_rest = async move {
+                    for plan in rest {
+                        let slot = match slots.clone().acquire_owned().await {
+                            Ok(slot) => slot,
+                            Err(_) => break,
+                        };
+                        if !delay.is_zero() {
+                            tokio::time::sleep(delay).await;
+                        }
+                        let res = backend.launch(&task_pool, plan, startup.clone(), Some(slot));
+                        if let Err(e) = res {
+                            log::error!("{}", e);
+                            break;
+                        }
+                    }
+                };
+                let stop = pool.shutdown_requested();
+                pool.spawn_task(async move {
+                    tokio::select! {
+                        _ = launch_rest => {}
+                        _ = stop => {}
+                    }
+                });
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+async fn log_output<R>(
+    pool: PoolHandle,
+    index: usize,
+    stream: R,
+    startup: Arc<Startup>,
+    slot: StartupSlot,
+    tail: OutputTail,
+) where
+    R: AsyncRead + Unpin,
+{
+    let mut lines = BufReader::new(stream).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        log::info!("{index}: {line}");
+        if line.contains(BOOTSTRAPPED_LINE)
+            && pool.replace_status(index, InstanceStatus::Starting, InstanceStatus::Ready)
+        {
+            slot.lock().unwrap().take();
+            let ready = startup.ready.fetch_add(1, Ordering::Relaxed) + 1;
+            log::info!("ready {}/{}", ready, startup.total);
+            pool.send_event(PoolEvent::Ready { index });
+            startup.changed.notify_one();
+        }
+        let mut tail = tail.lock().unwrap();
+        if tail.len() == OUTPUT_TAIL_LINES {
+            tail.pop_front();
+        }
+        tail.push_back(line);
+    }
+}
+
+#[cfg(unix)]
+fn kill_process_group(child: &Child) {
+    if let Some(pid) = child.id() {
+        unsafe { libc::killpg(pid as libc::pid_t, libc::SIGKILL) };
+    }
+}
+
+#[cfg(not(unix))]
+fn kill_process_group(_child: &Child) {}
+
+async fn watch_instance(
+    pool: PoolHandle,
+    index: usize,
+    mut child: Child,
+    data_dir: String,
+    startup: Arc<Startup>,
+    slot: StartupSlot,
+) {
+    let spawned = Instant::now();
+    let tail = OutputTail::default();
+    let mut readers = vec![];
+    if let Some(stdout) = child.stdout.take() {
+        let reader = log_output(pool.clone(), index, stdout, startup.clone(), slot.clone(), tail.clone());
+        readers.push(tokio::spawn(reader));
+    }
+    if let Some(stderr) = child.stderr.take() {
+        let reader = log_output(pool.clone(), index, stderr, startup.clone(), slot.clone(), tail.clone());
+        readers.push(tokio::spawn(reader));
+    }
+    tokio::select! {
+        res = child.wait() => {
+            // killed for exceeding limits (SIGXCPU, oom SIGKILL, ...) is an exit like any other
+            match &res {
+                Ok(status) => log::warn!("{index}: tor exited: {status}"),
+                Err(e) => log::warn!("{index}: tor wait failed: {e}"),
+            }
+            let code = res.ok().and_then(|x| x.code());
+            if !startup.early_exit.is_zero() && spawned.elapsed() < startup.early_exit {
+                let _ = tokio::time::timeout(OUTPUT_DRAIN_TIMEOUT, futures::future::join_all(readers)).await;
+                let output = tail.lock().unwrap().iter().cloned().collect();
+                startup.early_exits.lock().unwrap().push(EarlyExit { index, code, output });
+                startup.changed.notify_one();
+            }
+            pool.set_status(index, InstanceStatus::Exited { code });
+            pool.send_event(PoolEvent::Exited { index, code });
+        }
+        _ = pool.shutdown_requested() => {
+            kill_process_group(&child);
+            let _ = child.kill().await;
+            pool.set_status(index, InstanceStatus::Stopped);
+            pool.send_event(PoolEvent::Stopped { index });
+        }
+    }
+    slot.lock().unwrap().take();
+    data_dirs::remove_child_pid(&data_dir);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{PortModeConfig, TorConfig, TorDataDirsConfig};
+    use crate::error::TorSpawnError;
+    use crate::pool::SocksAddr;
+    #[cfg(target_os = "linux")]
+    use crate::tor_backend::spawn_child;
+    use crate::tor_backend::{parse_min_version, parse_tor_version, render_template, TorBackend};
+
+    // never touched: tests using it only compare strings
+    const ROOT: &str = "/nonexistent/dyn_tor";
+
+    fn backend(start_port: u16, port_count: u16, port_mode: PortModeConfig) -> TorBackend {
+        TorBackend::new(TorConfig {
+            path: "tor".to_string(),
+            torrc: "torrc".to_string(),
+            data_dirs: TorDataDirsConfig {
+                path: format!("{}/", ROOT).to_string(),
+                clear: false,
+                drop_files: vec![],
+                guard_max_age_days: None,
+                seed: false,
+                on_running_instance: Default::default(),
+                full_path: "".to_string(),
+            },
+            start_port,
+            port_count,
+            port_mode,
+            torrc_template: None,
+            torrc_check: Default::default(),
+            control_start_port: None,
+            groups: vec![],
+            credentials: None,
+            limits: Default::default(),
+            startup_concurrency: None,
+            startup_delay_ms: 0,
+            verify: false,
+            min_version: None,
+            early_exit_ms: 0,
+            max_early_exit_ratio: 0.5,
+            full_path: "".to_string(),
+            torrc_full_path: "".to_string(),
+            extra_args: vec![],
+            env: Default::default(),
+            clear_env: false,
+            working_dir: None,
+            wrapper: vec![],
+            working_dir_full_path: "".to_string(),
+        })
+    }
+
+    #[test]
+    fn check_allocate_ports() {
+        let busy = std::net::TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let busy_port = busy.local_addr().unwrap().port();
+        match backend(busy_port, 1, PortModeConfig::Fixed).allocate_ports() {
+            Err(TorSpawnError::PortsBusy { ports }) => assert_eq!(ports, busy_port.to_string()),
+            res => panic!("{:?}", res),
+        }
+
+        let mut ports = backend(0, 5, PortModeConfig::Auto).allocate_ports().unwrap();
+        ports.sort_unstable();
+        ports.dedup();
+        assert_eq!(ports.len(), 5);
+        assert!(!ports.contains(&0));
+    }
+
+    #[test]
+    fn check_unix_socket_plan() {
+        let plan = backend(0, 2, PortModeConfig::Unix).plan_instances().unwrap();
+        assert_eq!(plan[1].data_dir, "/nonexistent/dyn_tor/unix_1");
+        assert_eq!(plan[1].socks_addr, SocksAddr::Unix("/nonexistent/dyn_tor/unix_1/socks.sock".to_string()));
+
+        let mut long = backend(0, 1, PortModeConfig::Unix);
+        long.config.data_dirs.full_path = "/".to_string() + &"x".repeat(200) + "/";
+        assert!(matches!(
+            long.plan_instances(),
+            Err(TorSpawnError::SocketPathTooLong { .. })
+        ));
+    }
+
+    #[test]
+    fn check_parse_tor_version() {
+        assert_eq!(parse_tor_version("Tor version 0.4.7.13.\n"), Some(vec![0, 4, 7, 13]));
+        assert_eq!(
+            parse_tor_version("Tor version 0.4.8.0-alpha-dev (git-1234).\nTor is running on Linux"),
+            Some(vec![0, 4, 8, 0])
+        );
+        assert_eq!(parse_tor_version("command not found"), None);
+        assert!(Some(vec![0, 4, 7, 13]) < Some(vec![0, 4, 8]));
+
+        assert_eq!(parse_min_version("0.4.7").unwrap(), vec![0, 4, 7]);
+        for typo in ["0.4,7", "v0.4.7", "0.4.7.", "", "0.4.7.13.1", "0.4.x"] {
+            assert!(parse_min_version(typo).is_err(), "{}", typo);
+        }
+    }
+
+    #[test]
+    fn check_render_template() {
+        let mut config = backend(0, 3, PortModeConfig::Auto);
+        config.config.control_start_port = Some(0);
+        config.config.groups = vec!["us".to_string(), "de".to_string()];
+        let plan = config.plan_instances().unwrap();
+        let control_port = plan[2].control_port.unwrap();
+        assert!(plan.iter().all(|x| x.socks_addr != SocksAddr::Tcp(format!("127.0.0.1:{}", control_port))));
+        assert_eq!(plan[2].group.as_deref(), Some("us"));
+
+        let template = "ControlPort {control_port}\nExitNodes {{group}}\nStrictNodes 1\nLog notice file {data_dir}/{index}.log\n";
+        let socks_port = plan[2].socks_addr.to_string().rsplit(':').next().unwrap().to_string();
+        assert_eq!(
+            render_template(&(template.to_string() + "# {socks_port}"), &plan[2]).unwrap(),
+            format!(
+                "ControlPort {}\nExitNodes {{us}}\nStrictNodes 1\nLog notice file /nonexistent/dyn_tor/auto_2/2.log\n# {}",
+                control_port, socks_port
+            )
+        );
+
+        let plan = backend(0, 1, PortModeConfig::Unix).plan_instances().unwrap();
+        assert!(render_template(template, &plan[0]).is_err());
+        assert_eq!(
+            render_template("# {socks_port}", &plan[0]).unwrap(),
+            "# unix:/nonexistent/dyn_tor/unix_0/socks.sock"
+        );
+    }
+
+    #[test]
+    fn check_command() {
+        let mut config = backend(0, 1, PortModeConfig::Unix);
+        config.config.wrapper = vec!["nice".to_string(), "-n".to_string(), "5".to_string()];
+        config.config.extra_args = vec!["--Nickname".to_string(), "dyn{index}".to_string()];
+        config.config.env.insert("TOR_PT_STATE".to_string(), "1".to_string());
+        config.config.clear_env = true;
+        config.config.working_dir_full_path = ROOT.to_string();
+        let plan = config.plan_instances().unwrap();
+        let mut command = config.command();
+        command.args(config.instance_args(&plan[0], "/nonexistent/dyn_tor/unix_0/torrc").unwrap());
+        let command = command.as_std();
+        assert_eq!(command.get_program(), "nice");
+        let args: Vec<_> = command.get_args().map(|x| x.to_str().unwrap()).collect();
+        assert_eq!(
+            args,
+            vec![
+                "-n",
+                "5",
+                "tor",
+                "-f",
+                "/nonexistent/dyn_tor/unix_0/torrc",
+                "--SocksPort",
+                "unix:/nonexistent/dyn_tor/unix_0/socks.sock",
+                "--DataDirectory",
+                "/nonexistent/dyn_tor/unix_0",
+                "--Nickname",
+                "dyn0",
+            ]
+        );
+        let envs: Vec<_> = command.get_envs().collect();
+        assert_eq!(envs, vec![(std::ffi::OsStr::new("TOR_PT_STATE"), Some(std::ffi::OsStr::new("1")))]);
+        assert_eq!(command.get_current_dir(), Some(std::path::Path::new(ROOT)));
+    }
+
+    #[test]
+    fn check_describe_plan() {
+        // nothing is written: the data dirs root stays empty
+        let root = std::env::temp_dir().join(format!("dyn_tor_describe_{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let root_str = root.to_str().unwrap();
+        let mut config = backend(0, 2, PortModeConfig::Unix);
+        config.config.data_dirs.full_path = format!("{}/", root_str);
+        config.config.torrc_template = Some("Nickname dyn{index}\n".to_string());
+        config.config.extra_args = vec!["--Log".to_string(), "notice stdout".to_string()];
+        let plan = config.describe_plan().unwrap();
+        assert!(plan.contains(&format!(
+            "  command: tor -f {0}/unix_1/torrc --SocksPort unix:{0}/unix_1/socks.sock \
+             --DataDirectory {0}/unix_1 --Log 'notice stdout'\n",
+            root_str
+        )));
+        assert!(plan.contains(&format!("  torrc {}/unix_1/torrc:\n    Nickname dyn1\n", root_str)));
+        assert_eq!(std::fs::read_dir(&root).unwrap().count(), 0);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    // the child survives the thread that asked for it (pdeathsig would kill it otherwise)
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn check_spawn_child() {
+        let runtime = tokio::runtime::Handle::current();
+        let mut child = std::thread::spawn(move || {
+            let _runtime = runtime.enter();
+            let mut command = tokio::process::Command::new("sleep");
+            command.arg("5").kill_on_drop(true);
+            TorBackend::set_child_process_options(&mut command);
+            spawn_child(command).unwrap()
+        })
+        .join()
+        .unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        assert!(child.try_wait().unwrap().is_none());
+        child.kill().await.unwrap();
+    }
+}
@@ -0,0 +1,436 @@
+use crate::config::{TorConfig, TorrcCheckConfig};
+use crate::error::TorrcError;
+use std::path::{Path, PathBuf};
+
+pub const INCLUDE_DIRECTIVE: &str = "%include";
+
+// set by dyn_tor on tor command line, torrc values are replaced
+const COMMAND_LINE_OPTIONS: [&str; 2] = ["SocksPort", "DataDirectory"];
+
+// one value per instance is needed: fixed ones are bound by every instance
+const PORT_OPTIONS: [&str; 8] = [
+    "ControlPort",
+    "ORPort",
+    "DNSPort",
+    "TransPort",
+    "NATDPort",
+    "HTTPTunnelPort",
+    "ExtORPort",
+    "MetricsPort",
+];
+
+const PATH_OPTIONS: [&str; 10] = [
+    "CacheDirectory",
+    "KeyDirectory",
+    "GeoIPFile",
+    "GeoIPv6File",
+    "PidFile",
+    "CookieAuthFile",
+    "ControlPortWriteToFile",
+    "HiddenServiceDir",
+    "ClientOnionAuthDir",
+    "ServerDNSResolvConfFile",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TorrcEntry {
+    pub file: String,
+    // first line of the entry (continuations are joined)
+    pub line: usize,
+    pub key: String,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TorrcIssue {
+    pub severity: Severity,
+    pub file: String,
+    pub line: usize,
+    pub message: String,
+}
+
+impl std::fmt::Display for TorrcIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}: {}", self.file, self.line, self.message)
+    }
+}
+
+// (first line number, text) with backslash continuations joined;
+// comment lines inside a continuation are skipped, as tor does
+fn logical_lines(text: &str) -> Vec<(usize, String)> {
+    let mut res = vec![];
+    let mut current: Option<(usize, String)> = None;
+    for (number, line) in text.lines().enumerate() {
+        if current.is_some() && line.trim_start().starts_with('#') {
+            continue;
+        }
+        let (start, mut acc) = current.take().unwrap_or((number + 1, String::new()));
+        match line.trim_end().strip_suffix('\\') {
+            Some(part) => {
+                acc += part;
+                current = Some((start, acc));
+            }
+            None => {
+                acc += line;
+                res.push((start, acc));
+            }
+        }
+    }
+    res.extend(current);
+    res
+}
+
+// (key, value) of a line, None for empty lines and comments
+fn parse_line(line: &str) -> Result<Option<(String, String)>, String> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+    let (key, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let rest = rest.trim_start();
+    let value = match rest.strip_prefix('"') {
+        Some(quoted) => {
+            let mut value = String::new();
+            let mut chars = quoted.chars();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some('n') => value.push('\n'),
+                        Some('t') => value.push('\t'),
+                        Some(c) => value.push(c),
+                        None => return Err("unterminated quoted value".to_string()),
+                    },
+                    Some(c) => value.push(c),
+                    None => return Err("unterminated quoted value".to_string()),
+                }
+            }
+            value
+        }
+        None => rest.split('#').next().unwrap_or_default().trim_end().to_string(),
+    };
+    Ok(Some((key.to_string(), value)))
+}
+
+// directory includes its files (not dot files) in name order
+fn include_files(path: &Path) -> std::io::Result<Vec<PathBuf>> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let mut res = vec![];
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() && !entry.file_name().to_string_lossy().starts_with('.') {
+            res.push(entry.path());
+        }
+    }
+    res.sort();
+    Ok(res)
+}
+
+fn read_error(path: &Path, e: std::io::Error) -> TorrcError {
+    TorrcError::Read {
+        path: path.to_string_lossy().to_string(),
+        error: e.to_string(),
+    }
+}
+
+// `chain`: files being parsed, for include cycle detection
+fn parse_impl(text: &str, file: &str, dir: &Path, chain: &mut Vec<PathBuf>) -> Result<Vec<TorrcEntry>, TorrcError> {
+    let mut res = vec![];
+    for (line, text) in logical_lines(text) {
+        let (key, value) = match parse_line(&text) {
+            Ok(Some(x)) => x,
+            Ok(None) => continue,
+            Err(error) => {
+                return Err(TorrcError::Syntax {
+                    path: file.to_string(),
+                    line,
+                    error,
+                })
+            }
+        };
+        if key.eq_ignore_ascii_case(INCLUDE_DIRECTIVE) {
+            for path in include_files(&dir.join(&value)).map_err(|e| read_error(&dir.join(&value), e))? {
+                res.extend(parse_file_impl(&path, chain)?);
+            }
+            continue;
+        }
+        res.push(TorrcEntry {
+            file: file.to_string(),
+            line,
+            key,
+            value,
+        });
+    }
+    Ok(res)
+}
+
+fn parse_file_impl(path: &Path, chain: &mut Vec<PathBuf>) -> Result<Vec<TorrcEntry>, TorrcError> {
+    let canonical = path.canonicalize().map_err(|e| read_error(path, e))?;
+    if chain.contains(&canonical) {
+        chain.push(canonical);
+        let chain = chain.iter().map(|x| x.to_string_lossy()).collect::<Vec<_>>().join(" -> ");
+        return Err(TorrcError::IncludeCycle { chain });
+    }
+    let text = std::fs::read_to_string(path).map_err(|e| read_error(path, e))?;
+    chain.push(canonical);
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let res = parse_impl(&text, &path.to_string_lossy(), dir, chain);
+    chain.pop();
+    res
+}
+
+// Entries of a torrc with %include resolved against the including file location.
+pub fn parse_file(path: &Path) -> Result<Vec<TorrcEntry>, TorrcError> {
+    parse_file_impl(path, &mut vec![])
+}
+
+// `file` names the text in entries, %include is resolved against `dir`
+pub fn parse_str(text: &str, file: &str, dir: &Path) -> Result<Vec<TorrcEntry>, TorrcError> {
+    parse_impl(text, file, dir, &mut vec![])
+}
+
+fn find_option<'a>(options: &[&'a str], key: &str) -> Option<&'a str> {
+    options.iter().find(|x| x.eq_ignore_ascii_case(key)).copied()
+}
+
+// path argument of an option, `Log notice file <path>` included
+fn option_path<'a>(key: &str, value: &'a str) -> Option<&'a str> {
+    if key.eq_ignore_ascii_case("Log") {
+        let (_, path) = value.split_once(" file ")?;
+        return Some(path.trim());
+    }
+    if key.eq_ignore_ascii_case(INCLUDE_DIRECTIVE) {
+        return Some(value.trim());
+    }
+    find_option(&PATH_OPTIONS, key)?;
+    value.split_whitespace().next()
+}
+
+// {data_dir} and other placeholders render to absolute paths, tor expands '~'
+fn is_relative(path: &str) -> bool {
+    !Path::new(path).is_absolute() && !path.starts_with('{') && !path.starts_with('~')
+}
+
+fn quote(value: &str) -> String {
+    if !value.contains(|x: char| x.is_whitespace() || x == '#' || x == '"' || x == '\\') {
+        return value.to_string();
+    }
+    let escaped: String = value
+        .chars()
+        .map(|x| match x {
+            '"' => "\\\"".to_string(),
+            '\\' => "\\\\".to_string(),
+            '\n' => "\\n".to_string(),
+            '\t' => "\\t".to_string(),
+            x => x.to_string(),
+        })
+        .collect();
+    format!("\"{}\"", escaped)
+}
+
+// The line with its relative path made absolute against `dir`, None if there is nothing to do.
+fn absolutize_line(line: &str, dir: &Path) -> Option<String> {
+    let (key, value) = parse_line(line).ok()??;
+    let path = option_path(&key, &value).filter(|x| is_relative(x))?;
+    // the path is a slice of value
+    let start = path.as_ptr() as usize - value.as_ptr() as usize;
+    let absolute = dir.join(path).to_string_lossy().to_string();
+    let value = value[..start].to_string() + &absolute + &value[start + path.len()..];
+    Some(format!("{} {}", key, quote(&value)))
+}
+
+// Torrc text with relative paths of path options, `Log ... file` and %include made absolute
+// against `dir` (the torrc location): the rendered copy lives in the data dir and tor resolves
+// relative paths against its working directory. Included files are not rewritten.
+pub fn absolutize(text: &str, dir: &Path) -> String {
+    let mut res = String::new();
+    // physical lines of the logical line being read
+    let mut raw: Vec<&str> = vec![];
+    let mut logical = String::new();
+    for line in text.lines() {
+        raw.push(line);
+        if !logical.is_empty() && line.trim_start().starts_with('#') {
+            continue;
+        }
+        match line.trim_end().strip_suffix('\\') {
+            Some(part) => logical += part,
+            None => {
+                logical += line;
+                match absolutize_line(&logical, dir) {
+                    Some(line) => res += &(line + "\n"),
+                    None => raw.iter().for_each(|x| res += &(x.to_string() + "\n")),
+                }
+                raw.clear();
+                logical.clear();
+            }
+        }
+    }
+    raw.iter().for_each(|x| res += &(x.to_string() + "\n"));
+    res
+}
+
+// Conflicts with what dyn_tor passes to tor and relative paths dyn_tor can't make absolute:
+// ones in included files and in tor.torrc_template (no torrc location), tor would resolve them
+// against its working directory. Values with placeholders are not checked.
+pub fn check(entries: &[TorrcEntry], config: &TorConfig) -> Vec<TorrcIssue> {
+    let mut res = vec![];
+    for entry in entries {
+        let issue = |severity, message| TorrcIssue {
+            severity,
+            file: entry.file.clone(),
+            line: entry.line,
+            message,
+        };
+        let first = entry.value.split_whitespace().next().unwrap_or_default();
+        if let Some(option) = find_option(&COMMAND_LINE_OPTIONS, &entry.key) {
+            res.push(issue(
+                Severity::Warning,
+                format!("{} is set by dyn_tor for every instance, this value is ignored", option),
+            ));
+        } else if entry.key.eq_ignore_ascii_case("RunAsDaemon") && first == "1" {
+            res.push(issue(
+                Severity::Error,
+                "RunAsDaemon 1: dyn_tor needs tor to stay in foreground".to_string(),
+            ));
+        } else if let Some(option) = find_option(&PORT_OPTIONS, &entry.key) {
+            let fixed = !first.is_empty() && first != "0" && !first.eq_ignore_ascii_case("auto") && !first.contains('{');
+            if fixed && config.port_count > 1 {
+                res.push(issue(
+                    Severity::Error,
+                    format!(
+                        "{} {}: every instance would bind it, use 'auto' or a placeholder like {{control_port}}",
+                        option, first
+                    ),
+                ));
+            }
+        }
+        let absolutized = config.torrc_template.is_none() && entry.file == config.torrc_full_path;
+        if let Some(path) = option_path(&entry.key, &entry.value).filter(|x| is_relative(x)) {
+            if !absolutized && !entry.key.eq_ignore_ascii_case(INCLUDE_DIRECTIVE) {
+                res.push(issue(
+                    Severity::Error,
+                    format!(
+                        "relative path '{}' would be resolved by tor against its working directory, use an absolute path",
+                        path
+                    ),
+                ));
+            }
+        }
+    }
+    res
+}
+
+// Parses the torrc (or tor.torrc_template) and checks it, nothing when the check is off.
+pub fn check_config(config: &TorConfig) -> Result<Vec<TorrcIssue>, TorrcError> {
+    if config.torrc_check == TorrcCheckConfig::Off {
+        return Ok(vec![]);
+    }
+    let entries = match &config.torrc_template {
+        Some(template) => {
+            let dir = std::env::current_dir().map_err(|e| read_error(Path::new("."), e))?;
+            parse_str(template, "tor.torrc_template", &dir)?
+        }
+        None => parse_file(Path::new(&config.torrc_full_path))?,
+    };
+    Ok(check(&entries, config))
+}
+
+// errors (and warnings in Strict mode) fail the check
+pub fn enforce(config: &TorConfig, issues: &[TorrcIssue]) -> Result<(), TorrcError> {
+    let failed: Vec<String> = issues
+        .iter()
+        .filter(|x| x.severity == Severity::Error || config.torrc_check == TorrcCheckConfig::Strict)
+        .map(|x| x.to_string())
+        .collect();
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(TorrcError::Conflicts {
+            issues: failed.join("\n"),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::TorConfig;
+    use crate::error::TorrcError;
+    use crate::torrc::{absolutize, check, logical_lines, parse_file, parse_str, Severity};
+    use std::path::Path;
+
+    #[test]
+    fn check_torrc_parse() {
+        assert_eq!(
+            logical_lines("a 1\nb 2 \\\n# skipped\n 3\nc"),
+            vec![(1, "a 1".to_string()), (2, "b 2  3".to_string()), (5, "c".to_string())]
+        );
+
+        let dir = std::env::temp_dir().join(format!("dyn_tor_torrc_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("torrc.d")).unwrap();
+        std::fs::write(
+            dir.join("torrc"),
+            "# comment\nSocksPort 9050 # default\nLog \"notice file tor log.txt\"\n%include torrc.d\nControlPort 9051\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("torrc.d/10-daemon"), "\nRunAsDaemon 1\nPidFile tor.pid\n").unwrap();
+        std::fs::write(dir.join("torrc.d/.hidden"), "RunAsDaemon 0\n").unwrap();
+        let entries = parse_file(&dir.join("torrc")).unwrap();
+        let keys: Vec<_> = entries.iter().map(|x| (x.key.as_str(), x.value.as_str(), x.line)).collect();
+        assert_eq!(
+            keys,
+            vec![
+                ("SocksPort", "9050", 2),
+                ("Log", "notice file tor log.txt", 3),
+                ("RunAsDaemon", "1", 2),
+                ("PidFile", "tor.pid", 3),
+                ("ControlPort", "9051", 5),
+            ]
+        );
+
+        let mut config: TorConfig = serde_json::from_str(
+            r#"{"path": "tor", "torrc": "torrc", "data_dirs": {"path": "data", "clear": false},
+                "start_port": 9060, "port_count": 2}"#,
+        )
+        .unwrap();
+        config.torrc_full_path = dir.join("torrc").to_string_lossy().to_string();
+        // relative paths of the torrc itself are made absolute, of included files they are errors
+        let issues = check(&entries, &config);
+        let lines: Vec<_> = issues.iter().map(|x| (x.severity, x.line)).collect();
+        assert_eq!(
+            lines,
+            vec![
+                (Severity::Warning, 2),
+                (Severity::Error, 2),
+                (Severity::Error, 3),
+                (Severity::Error, 5),
+            ]
+        );
+        assert!(issues[2].message.contains("'tor.pid'"));
+        config.torrc_template = Some("PidFile tor.pid\nCacheDirectory {data_dir}/cache\n".to_string());
+        let entries = parse_str(config.torrc_template.as_ref().unwrap(), "tor.torrc_template", &dir).unwrap();
+        assert_eq!(check(&entries, &config).len(), 1);
+
+        std::fs::write(dir.join("torrc.d/20-loop"), "%include ../torrc\n").unwrap();
+        assert!(matches!(parse_file(&dir.join("torrc")), Err(TorrcError::IncludeCycle { .. })));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn check_torrc_absolutize() {
+        let text = "Log \"notice file tor log.txt\"\nPidFile run/tor.pid # pid\nDataDirectory /abs\n\
+                    GeoIPFile \\\n  geoip\nCacheDirectory {data_dir}/cache\n%include torrc.d\nSocksPort 9050\n";
+        assert_eq!(
+            absolutize(text, Path::new("/etc/tor")),
+            "Log \"notice file /etc/tor/tor log.txt\"\nPidFile /etc/tor/run/tor.pid\nDataDirectory /abs\n\
+             GeoIPFile /etc/tor/geoip\nCacheDirectory {data_dir}/cache\n%include /etc/tor/torrc.d\nSocksPort 9050\n"
+        );
+    }
+}
@@ -0,0 +1,184 @@
+use crate::backend::Backend;
+use crate::config::UpstreamConfig;
+use crate::error::BackendError;
+use crate::error::SocksError;
+use crate::pool::{InstanceInfo, InstanceStatus, PoolEvent, PoolHandle, SocksAddr};
+use crate::socks;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+pub const KIND: &str = "upstream";
+
+// pre-existing socks proxies (remote tor daemons, commercial proxies)
+pub struct UpstreamBackend {
+    upstreams: Vec<UpstreamConfig>,
+}
+
+impl UpstreamBackend {
+    pub fn new(upstreams: Vec<UpstreamConfig>) -> Self {
+        Self { upstreams }
+    }
+
+    pub fn check(&self) -> Result<(), BackendError> {
+        for upstream in &self.upstreams {
+            check_addr(&upstream.addr)?;
+        }
+        Ok(())
+    }
+}
+
+fn check_addr(addr: &str) -> Result<(), BackendError> {
+    let error = |error: &str| BackendError::InvalidUpstream {
+        addr: addr.to_string(),
+        error: error.to_string(),
+    };
+    match addr.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() => port
+            .parse::<u16>()
+            .map(|_| ())
+            .map_err(|e| error(&e.to_string())),
+        _ => Err(error("expected 'host:port'")),
+    }
+}
+
+// connects and completes the socks5 greeting (and authentication, if configured)
+async fn check_upstream(upstream: &UpstreamConfig) -> Result<(), Box<dyn std::error::Error>> {
+    let mut stream = TcpStream::connect(&upstream.addr).await?;
+    match &upstream.credentials {
+        Some(credentials) => socks::upstream_auth(&mut stream, credentials).await,
+        None => {
+            stream.write_all(&[socks::VERSION, 1, socks::METHOD_NO_AUTH]).await?;
+            let mut reply = [0u8; 2];
+            stream.read_exact(&mut reply).await?;
+            if reply != [socks::VERSION, socks::METHOD_NO_AUTH] {
+                return Err(SocksError::UpstreamNoAuth.into());
+            }
+            Ok(())
+        }
+    }
+}
+
+// Ready <-> Unhealthy by periodic checks until shutdown, like tor instances report their exit.
+async fn watch_upstream(pool: PoolHandle, index: usize, upstream: UpstreamConfig) {
+    let interval = Duration::from_millis(upstream.check_interval_ms);
+    let timeout = Duration::from_millis(upstream.check_timeout_ms);
+    let checks = async {
+        loop {
+            let res = match tokio::time::timeout(timeout, check_upstream(&upstream)).await {
+                Ok(res) => res.map_err(|e| e.to_string()),
+                Err(_) => Err(format!("no answer in {}ms", upstream.check_timeout_ms)),
+            };
+            match res {
+                Ok(()) => {
+                    if pool.replace_status(index, InstanceStatus::Unhealthy, InstanceStatus::Ready) {
+                        log::info!("upstream {} is back", upstream.addr);
+                        pool.send_event(PoolEvent::Ready { index });
+                    }
+                }
+                Err(e) => {
+                    if pool.replace_status(index, InstanceStatus::Ready, InstanceStatus::Unhealthy) {
+                        log::warn!("upstream {} failed health check: {}", upstream.addr, e);
+                        pool.send_event(PoolEvent::Unhealthy { index });
+                    }
+                }
+            }
+            tokio::time::sleep(interval).await;
+        }
+    };
+    tokio::select! {
+        _ = checks => {}
+        _ = pool.shutdown_requested() => {}
+    }
+    pool.set_status(index, InstanceStatus::Stopped);
+    pool.send_event(PoolEvent::Stopped { index });
+}
+
+impl Backend for UpstreamBackend {
+    fn kind(&self) -> &'static str {
+        KIND
+    }
+
+    fn start(&self, pool: PoolHandle) -> BoxFuture<'_, Result<(), BackendError>> {
+        async move {
+            self.check()?;
+            for upstream in &self.upstreams {
+                let index = pool.add_instance(InstanceInfo {
+                    index: 0,
+                    backend: KIND,
+                    socks_addr: SocksAddr::Tcp(upstream.addr.clone()),
+                    credentials: upstream.credentials.clone(),
+                    data_dir: None,
+                    group: None,
+                    pid: None,
+                    status: InstanceStatus::Ready,
+                });
+                pool.send_event(PoolEvent::Ready { index });
+                if upstream.check_interval_ms > 0 {
+                    pool.spawn_task(watch_upstream(pool.clone(), index, upstream.clone()));
+                }
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::UpstreamConfig;
+    use crate::pool::{InstanceStatus, PoolEvent, TorPool};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio_stream::StreamExt;
+
+    #[tokio::test]
+    async fn check_upstream_health() {
+        // socks5 server that refuses every method while `down` is set
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let down = Arc::new(AtomicBool::new(false));
+        let server_down = down.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut greeting = [0u8; 3];
+                if stream.read_exact(&mut greeting).await.is_ok() {
+                    let method = if server_down.load(Ordering::SeqCst) { 0xff } else { 0 };
+                    let _ = stream.write_all(&[5, method]).await;
+                }
+            }
+        });
+
+        let pool = TorPool::builder()
+            .upstreams(vec![UpstreamConfig {
+                addr,
+                credentials: None,
+                check_interval_ms: 20,
+                check_timeout_ms: 1000,
+            }])
+            .build();
+        let mut events = Box::pin(pool.events());
+        pool.start().await.unwrap();
+        assert!(matches!(events.next().await, Some(PoolEvent::Ready { index: 0 })));
+
+        down.store(true, Ordering::SeqCst);
+        let event = tokio::time::timeout(Duration::from_secs(5), events.next()).await.unwrap();
+        assert!(matches!(event, Some(PoolEvent::Unhealthy { index: 0 })));
+        assert_eq!(pool.instance_status(0).unwrap().status, InstanceStatus::Unhealthy);
+        assert!(pool.pick_instance().is_none());
+
+        down.store(false, Ordering::SeqCst);
+        let event = tokio::time::timeout(Duration::from_secs(5), events.next()).await.unwrap();
+        assert!(matches!(event, Some(PoolEvent::Ready { index: 0 })));
+        assert!(pool.pick_instance().is_some());
+
+        pool.shutdown().await;
+        assert_eq!(pool.instance_status(0).unwrap().status, InstanceStatus::Stopped);
+    }
+}
@@ -0,0 +1,319 @@
+// End-to-end: the dyn_tor binary with examples/fake_tor.rs as tor, all on localhost.
+// `cargo test` builds the example, `cargo test --test e2e` doesn't: then
+// `cargo build --example fake_tor` first.
+#![cfg(unix)]
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::time::{Duration, Instant};
+
+const START_TIMEOUT: Duration = Duration::from_secs(30);
+const EXIT_TIMEOUT: Duration = Duration::from_secs(15);
+
+fn fake_tor_path() -> PathBuf {
+    // target/<profile>/deps/e2e-<hash> -> target/<profile>/examples/fake_tor
+    let mut path = std::env::current_exe().unwrap();
+    path.pop();
+    path.pop();
+    path.push("examples");
+    path.push("fake_tor");
+    let source = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples/fake_tor.rs");
+    let modified = |x: &Path| std::fs::metadata(x).and_then(|x| x.modified()).ok();
+    assert!(
+        modified(&path) >= modified(&source),
+        "{} missing or outdated: cargo build --example fake_tor",
+        path.display()
+    );
+    path
+}
+
+fn free_port() -> u16 {
+    std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+// dyn_tor copied into its own dir with dyn_tor.config next to it, so paths, pid file and data
+// dirs of parallel tests don't meet
+struct Setup {
+    dir: PathBuf,
+    listen_addr: String,
+}
+
+impl Setup {
+    fn new(name: &str, port_count: u16, torrc: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("dyn_tor_e2e_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::copy(env!("CARGO_BIN_EXE_dyn_tor"), dir.join("dyn_tor")).unwrap();
+        std::fs::write(dir.join("torrc"), torrc).unwrap();
+        let listen_addr = format!("127.0.0.1:{}", free_port());
+        let config = serde_json::json!({
+            "tor": {
+                "path": fake_tor_path().to_str().unwrap(),
+                "torrc": "./torrc",
+                "data_dirs": {"path": "./data_dirs", "clear": true},
+                "start_port": 0,
+                "port_count": port_count,
+                "port_mode": "Auto",
+                "early_exit_ms": 500
+            },
+            "listen_addr": listen_addr,
+            "log": {"use": false, "path": "./", "level": "Info"}
+        });
+        std::fs::write(dir.join("dyn_tor.config"), config.to_string()).unwrap();
+        Self { dir, listen_addr }
+    }
+
+    fn command(&self) -> Command {
+        let mut command = Command::new(self.dir.join("dyn_tor"));
+        command.current_dir(&self.dir);
+        command
+    }
+
+    fn spawn(self) -> Running {
+        let stdout = std::fs::File::create(self.dir.join("stdout.log")).unwrap();
+        let child = self
+            .command()
+            .stdout(stdout.try_clone().unwrap())
+            .stderr(stdout)
+            .spawn()
+            .unwrap();
+        let res = Running { setup: self, child };
+        res.wait_ready();
+        res
+    }
+
+    // dyn_tor expected to fail: its exit status and output
+    fn run(&self) -> (ExitStatus, String) {
+        let mut child = self.command().stdout(Stdio::piped()).stderr(Stdio::piped()).spawn().unwrap();
+        let status = wait_timeout(&mut child, START_TIMEOUT).expect("dyn_tor didn't exit");
+        let mut output = String::new();
+        child.stdout.take().unwrap().read_to_string(&mut output).unwrap();
+        child.stderr.take().unwrap().read_to_string(&mut output).unwrap();
+        (status, output)
+    }
+
+    // pids of running tor instances by data dir name
+    fn instance_pids(&self) -> Vec<(String, i32)> {
+        let mut res: Vec<_> = std::fs::read_dir(self.dir.join("data_dirs"))
+            .unwrap()
+            .flatten()
+            .filter_map(|x| {
+                let pid = std::fs::read_to_string(x.path().join("dyn_tor.pid")).ok()?;
+                Some((x.file_name().to_string_lossy().to_string(), pid.trim().parse().ok()?))
+            })
+            .collect();
+        res.sort();
+        res
+    }
+}
+
+impl Drop for Setup {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+struct Running {
+    setup: Setup,
+    child: Child,
+}
+
+impl Running {
+    fn output(&self) -> String {
+        std::fs::read_to_string(self.setup.dir.join("stdout.log")).unwrap_or_default()
+    }
+
+    fn wait_ready(&self) {
+        let start = Instant::now();
+        while connect(&self.setup.listen_addr).is_err() {
+            assert!(start.elapsed() < START_TIMEOUT, "dyn_tor didn't start:\n{}", self.output());
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn wait_timeout(child: &mut Child, timeout: Duration) -> Option<ExitStatus> {
+    let start = Instant::now();
+    while start.elapsed() < timeout {
+        if let Some(status) = child.try_wait().unwrap() {
+            return Some(status);
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    None
+}
+
+fn is_running(pid: i32) -> bool {
+    unsafe { libc::kill(pid, 0) == 0 }
+}
+
+// socks5 CONNECT through dyn_tor; returns the stream and the name of the instance behind it
+fn connect(addr: &str) -> std::io::Result<(BufReader<TcpStream>, String)> {
+    let mut stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.write_all(&[5, 1, 0])?;
+    stream.write_all(b"\x05\x01\x00\x03\x0bexample.com\x00\x50")?;
+    let mut reply = [0u8; 12];
+    stream.read_exact(&mut reply)?;
+    assert_eq!(reply, [5, 0, 5, 0, 0, 1, 127, 0, 0, 1, 0, 80]);
+    let mut stream = BufReader::new(stream);
+    let mut name = String::new();
+    stream.read_line(&mut name)?;
+    if name.is_empty() {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok((stream, name.trim().to_string()))
+}
+
+fn instance_names(addr: &str, count: usize) -> Vec<String> {
+    (0..count).map(|_| connect(addr).unwrap().1).collect()
+}
+
+#[test]
+fn check_round_robin() {
+    let running = Setup::new("round_robin", 3, "").spawn();
+    let addr = &running.setup.listen_addr;
+    let names = instance_names(addr, 6);
+    assert_eq!(names[..3], names[3..]);
+    let mut distinct = names[..3].to_vec();
+    distinct.sort();
+    distinct.dedup();
+    assert_eq!(distinct.len(), 3, "{:?}", names);
+
+    // relayed both ways
+    let (mut stream, _) = connect(addr).unwrap();
+    stream.get_mut().write_all(b"ping\n").unwrap();
+    let mut echo = String::new();
+    stream.read_line(&mut echo).unwrap();
+    assert_eq!(echo, "ping\n");
+}
+
+#[test]
+fn check_instance_crash() {
+    let mut running = Setup::new("crash", 3, "").spawn();
+    let pids = running.setup.instance_pids();
+    assert_eq!(pids.len(), 3);
+    let (crashed, pid) = &pids[1];
+    unsafe { libc::kill(*pid, libc::SIGKILL) };
+
+    // until dyn_tor has seen the exit, clients sent to the crashed instance are dropped
+    let addr = &running.setup.listen_addr;
+    let start = Instant::now();
+    let names = loop {
+        let names: Result<Vec<_>, _> = (0..4).map(|_| connect(addr).map(|x| x.1)).collect();
+        match names {
+            Ok(names) if !names.contains(crashed) => break names,
+            _ => assert!(start.elapsed() < START_TIMEOUT, "crashed instance still picked"),
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    };
+    assert!(names.contains(&pids[0].0) && names.contains(&pids[2].0), "{:?}", names);
+    assert!(running.child.try_wait().unwrap().is_none(), "{}", running.output());
+}
+
+#[test]
+fn check_rejected_config() {
+    let setup = Setup::new("rejected", 2, "FakeTorReject 1\n");
+    let (status, output) = setup.run();
+    assert!(!status.success());
+    assert!(output.contains("tor rejected configuration"), "{}", output);
+    assert!(output.contains("Unknown option 'FakeTorReject'"), "{}", output);
+    assert!(setup.instance_pids().is_empty());
+}
+
+#[test]
+fn check_crash_on_start() {
+    let setup = Setup::new("early_exit", 2, "FakeTorExit 3\n");
+    let (status, output) = setup.run();
+    assert!(!status.success());
+    assert!(output.contains("2 of 2 tor instances exited right after start"), "{}", output);
+    assert!(output.contains("exiting as asked by FakeTorExit"), "{}", output);
+}
+
+#[test]
+fn check_shutdown() {
+    let mut running = Setup::new("shutdown", 2, "").spawn();
+    let pids = running.setup.instance_pids();
+    assert_eq!(pids.len(), 2);
+    assert!(pids.iter().all(|(_, pid)| is_running(*pid)));
+
+    unsafe { libc::kill(running.child.id() as i32, libc::SIGINT) };
+    let status = wait_timeout(&mut running.child, EXIT_TIMEOUT).expect("dyn_tor didn't stop");
+    assert!(status.success(), "{}", running.output());
+    assert!(pids.iter().all(|(_, pid)| !is_running(*pid)), "tor instances left running");
+    assert!(TcpStream::connect(&running.setup.listen_addr).is_err());
+    // pid file lock, data dirs and listen address are free for the next start
+    let dry_run = running.setup.command().arg("--dry-run").output().unwrap();
+    assert!(dry_run.status.success(), "{}", String::from_utf8_lossy(&dry_run.stdout));
+}
+
+#[test]
+fn check_access_list_reload() {
+    let running = Setup::new("access", 1, "").spawn();
+    let path = running.setup.dir.join("dyn_tor.config");
+    let mut config: serde_json::Value = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+    config["proxy"] = serde_json::json!({"allow": ["10.0.0.0/8"]});
+    std::fs::write(&path, config.to_string()).unwrap();
+    unsafe { libc::kill(running.child.id() as i32, libc::SIGHUP) };
+
+    let start = Instant::now();
+    while connect(&running.setup.listen_addr).is_ok() {
+        assert!(start.elapsed() < START_TIMEOUT, "client not denied after reload");
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    // broken config: the lists in use stay
+    std::fs::write(&path, "{").unwrap();
+    unsafe { libc::kill(running.child.id() as i32, libc::SIGHUP) };
+    std::thread::sleep(Duration::from_millis(200));
+    assert!(connect(&running.setup.listen_addr).is_err());
+}
+
+// socks5 with username/password: the auth status byte dyn_tor replied
+fn connect_as(addr: &str, username: &str, password: &str) -> std::io::Result<u8> {
+    let mut stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.write_all(&[5, 1, 2])?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply)?;
+    assert_eq!(reply, [5, 2]);
+    stream.write_all(&[1, username.len() as u8])?;
+    stream.write_all(username.as_bytes())?;
+    stream.write_all(&[password.len() as u8])?;
+    stream.write_all(password.as_bytes())?;
+    stream.read_exact(&mut reply)?;
+    Ok(reply[1])
+}
+
+#[test]
+fn check_user_auth() {
+    let running = Setup::new("auth", 1, "").spawn();
+    let path = running.setup.dir.join("dyn_tor.config");
+    let mut config: serde_json::Value = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+    let hash = dyn_tor::password::PasswordHash::new("secret", 10).unwrap().to_string();
+    config["proxy"] = serde_json::json!({"users": [{"username": "alice", "password_hash": hash}]});
+    std::fs::write(&path, config.to_string()).unwrap();
+    unsafe { libc::kill(running.child.id() as i32, libc::SIGHUP) };
+
+    let addr = &running.setup.listen_addr;
+    let start = Instant::now();
+    while connect(addr).is_ok() {
+        assert!(start.elapsed() < START_TIMEOUT, "no authentication asked after reload");
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    assert_ne!(connect_as(addr, "alice", "wrong").unwrap(), 0);
+    assert_ne!(connect_as(addr, "bob", "secret").unwrap(), 0);
+    assert_eq!(connect_as(addr, "alice", "secret").unwrap(), 0);
+}